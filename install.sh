@@ -50,6 +50,7 @@ apt-get install -y libasound2-dev
 apt-get install -y libwebkit2gtk-4.0
 apt-get install -y libssl-dev
 apt-get install -y chrony
+apt-get install -y flac
 
 systemctl enable --now chrony
 # sudo apt-get install cmake;
@@ -1,14 +1,26 @@
-use hound::{SampleFormat, WavWriter};
+use crossbeam_channel::Sender;
+use hound::{SampleFormat, WavSpec, WavWriter};
+use log::{error, info, warn};
 use parking_lot::Mutex;
-use std::path::PathBuf;
-use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU8, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::thread::{self, Scope};
 use std::time::{Duration, Instant};
 
-use alsa::{
-    pcm::{Access, Format, HwParams, PCM},
-    Direction, Error, ValueOr,
-};
+use alsa::{pcm::Format, Error};
+#[cfg(not(feature = "mock-hardware"))]
+use alsa::device_name::HintIter;
+#[cfg(not(feature = "mock-hardware"))]
+use alsa::pcm::{Access, HwParams, IO, PCM};
+#[cfg(not(feature = "mock-hardware"))]
+use alsa::{Direction, ValueOr};
+
+#[cfg(feature = "mock-hardware")]
+use crate::mock::{Pcm as PCM, IO};
 
 #[derive(thiserror::Error, Debug)]
 pub enum CaptureDeviceError {
@@ -18,23 +30,197 @@ pub enum CaptureDeviceError {
     Alsa(#[from] alsa::Error),
     #[error("Hound error: {0}")]
     Hound(#[from] hound::Error),
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("No samples for {0:?}, reinitializing device")]
+    Stalled(Duration),
 }
 
-pub struct CaptureDevice<'a> {
+/// Names of the PCM devices ALSA offers for capture
+#[cfg(not(feature = "mock-hardware"))]
+pub fn capture_device_names() -> Result<Vec<String>, Error> {
+    Ok(HintIter::new_str(None, "pcm")?
+        .filter(|hint| hint.direction != Some(Direction::Playback))
+        .filter_map(|hint| hint.name)
+        .collect())
+}
+
+/// Hardware parameters the card actually granted, which may differ from the requested ones
+#[derive(Debug, Clone)]
+struct Negotiated {
+    /// Candidate name the device was opened with
     device_name: String,
     channels: u32,
     samplerate: u32,
     format: Format,
+}
+
+/// Reads blocks of samples in the negotiated format promoted to full scale `i32`, so files and
+/// peaks look the same whatever the card delivers.
+enum SampleReader<'p> {
+    S32(IO<'p, i32>),
+    S16(IO<'p, i16>, Vec<i16>),
+    /// 24 bit little endian samples, either packed into 3 bytes or in the low bytes of 4
+    S24(IO<'p, u8>, Vec<u8>, usize),
+}
+
+impl<'p> SampleReader<'p> {
+    /// Reads into buffers of `samples` samples.
+    fn new(pcm: &'p PCM, format: Format, samples: usize) -> Result<Self, CaptureDeviceError> {
+        Ok(match format {
+            Format::S32LE | Format::S32BE => Self::S32(pcm.io_i32()?),
+            Format::S16LE | Format::S16BE => Self::S16(pcm.io_i16()?, vec![0; samples]),
+            Format::S243LE => Self::S24(pcm.io_bytes(), vec![0; samples * 3], 3),
+            Format::S24LE => Self::S24(pcm.io_bytes(), vec![0; samples * 4], 4),
+            default => return Err(CaptureDeviceError::FormatUnimplemented(default)),
+        })
+    }
+
+    /// Returns the number of frames read into `buf`.
+    fn readi(&mut self, buf: &mut [i32]) -> Result<usize, Error> {
+        match self {
+            Self::S32(io) => io.readi(buf),
+            Self::S16(io, raw) => {
+                let frames = io.readi(raw)?;
+                for (sample, raw) in buf.iter_mut().zip(raw.iter()) {
+                    *sample = i32::from(*raw) << 16;
+                }
+                Ok(frames)
+            }
+            Self::S24(io, raw, width) => {
+                let frames = io.readi(raw)?;
+                for (sample, raw) in buf.iter_mut().zip(raw.chunks_exact(*width)) {
+                    *sample = i32::from_le_bytes([0, raw[0], raw[1], raw[2]]);
+                }
+                Ok(frames)
+            }
+        }
+    }
+}
+
+/// A WAV file written under a `.partial` name and renamed once finalized, so any `.wav` file
+/// in the output directory is complete.
+struct AudioFile {
+    writer: WavWriter<BufWriter<File>>,
+    path: PathBuf,
+}
+
+impl AudioFile {
+    fn create(path: PathBuf, spec: WavSpec) -> Result<Self, CaptureDeviceError> {
+        let writer = WavWriter::create(Self::partial_path(&path), spec)?;
+        Ok(Self { writer, path })
+    }
+
+    fn partial_path(path: &Path) -> PathBuf {
+        path.with_extension("wav.partial")
+    }
+
+    /// Returns the path of the complete file.
+    fn finalize(self) -> Result<PathBuf, CaptureDeviceError> {
+        self.writer.finalize()?;
+        std::fs::rename(Self::partial_path(&self.path), &self.path)?;
+        Ok(self.path)
+    }
+}
+
+/// Compresses finished WAV files to FLAC with the `flac` tool, removing each WAV once it's
+/// done. Files are encoded one at a time on a worker thread, so the capture loop never waits for
+/// it, and a WAV is kept if encoding fails or the queue is full. 32 bit samples need flac 1.4 or
+/// newer.
+#[derive(Clone)]
+pub struct FlacEncoder {
+    tx: Sender<PathBuf>,
+}
+
+impl FlacEncoder {
+    /// Files waiting to be encoded before new ones are kept as WAV
+    const QUEUE: usize = 8;
+
+    /// Starts the worker, which exits once every clone is dropped. Returns `None` if the `flac`
+    /// tool can't be run.
+    pub fn spawn<'a>(s: &'a Scope<'a, '_>) -> Option<Self> {
+        match Command::new("flac").arg("--version").output() {
+            Ok(output) if output.status.success() => {
+                info!("Using {}", String::from_utf8_lossy(&output.stdout).trim());
+            }
+            Ok(output) => {
+                error!("flac --version failed with {}, keeping WAV files", output.status);
+                return None;
+            }
+            Err(err) => {
+                error!("Failed to run flac, keeping WAV files: {err}");
+                return None;
+            }
+        }
+        let (tx, rx) = crossbeam_channel::bounded::<PathBuf>(Self::QUEUE);
+        let spawned = thread::Builder::new()
+            .name("flac".to_owned())
+            .spawn_scoped(s, move || {
+                for path in rx {
+                    Self::encode(&path);
+                }
+            });
+        match spawned {
+            Ok(_) => Some(Self { tx }),
+            Err(err) => {
+                error!("Failed to spawn flac thread, keeping WAV files: {err}");
+                None
+            }
+        }
+    }
+
+    fn encode(path: &Path) {
+        let status = Command::new("flac")
+            .args(["--silent", "--delete-input-file", "-o"])
+            .arg(path.with_extension("flac"))
+            .arg(path)
+            .status();
+        match status {
+            Ok(status) if status.success() => {}
+            Ok(status) => warn!("flac failed on {}: {status}", path.display()),
+            Err(err) => warn!("Failed to run flac on {}: {err}", path.display()),
+        }
+    }
+
+    /// Queues `path` for encoding.
+    fn push(&self, path: PathBuf) {
+        if let Err(err) = self.tx.try_send(path) {
+            let path = err.into_inner();
+            warn!("FLAC encoding is behind, keeping {}", path.display());
+        }
+    }
+}
+
+pub struct CaptureDevice<'a> {
+    /// Names tried in order until one opens, USB cards get renamed when they enumerate in a
+    /// different order
+    device_names: Vec<String>,
+    channels: u32,
+    samplerate: u32,
+    format: Format,
     output_dir: PathBuf,
     running: &'a AtomicBool,
     status: &'a AtomicU8,
     pps: Arc<Mutex<(bool, i64)>>,
+    watchdog: Duration,
+    checkpoint: Option<&'a AtomicUsize>,
+    peaks: Option<&'a [AtomicI32]>,
+    warmup_blocks: usize,
+    low_space: Option<&'a AtomicBool>,
+    file_template: String,
+    flac: Option<FlacEncoder>,
 }
 
 #[allow(clippy::too_many_arguments)]
 impl<'a> CaptureDevice<'a> {
+    pub const DEFAULT_WATCHDOG: Duration = Duration::from_secs(10);
+    pub const DEFAULT_WARMUP_BLOCKS: usize = 16;
+    /// Samples read at a time, rounded down to whole frames
+    const BLOCK_SAMPLES: usize = 1024;
+    const DEFAULT_FILE_TEMPLATE: &'static str = "{nanos}";
+
     pub fn new<P: Into<PathBuf>>(
-        device_name: &str,
+        device_names: &[String],
         channels: u32,
         samplerate: u32,
         format: Format,
@@ -44,7 +230,7 @@ impl<'a> CaptureDevice<'a> {
         pps: Arc<Mutex<(bool, i64)>>,
     ) -> Self {
         Self {
-            device_name: device_name.to_owned(),
+            device_names: device_names.to_vec(),
             channels,
             samplerate,
             format,
@@ -52,16 +238,93 @@ impl<'a> CaptureDevice<'a> {
             running,
             status,
             pps,
+            watchdog: Self::DEFAULT_WATCHDOG,
+            checkpoint: None,
+            peaks: None,
+            warmup_blocks: Self::DEFAULT_WARMUP_BLOCKS,
+            low_space: None,
+            file_template: Self::DEFAULT_FILE_TEMPLATE.to_owned(),
+            flac: None,
         }
     }
 
     #[allow(unused)]
     pub fn set_device_name(&mut self, device_name: &str) {
-        device_name.clone_into(&mut self.device_name);
+        self.device_names = vec![device_name.to_owned()];
+    }
+
+    /// Sets how long the device may deliver only silence before it is reinitialized.
+    pub fn set_watchdog(&mut self, watchdog: Duration) {
+        self.watchdog = watchdog;
+    }
+
+    /// Sets how many blocks read right after opening the device are discarded, the ADC output
+    /// is unreliable until it settles.
+    pub fn set_warmup_blocks(&mut self, blocks: usize) {
+        self.warmup_blocks = blocks;
+    }
+
+    /// Sets the name of new files, without the extension. `{nanos}` is replaced with the
+    /// creation time in nanoseconds since the epoch and `{time}` with it as an ISO-8601 UTC
+    /// timestamp in milliseconds with `-` in place of `:`. A `_1`, `_2`, ... suffix is added
+    /// if a file with the name already exists.
+    pub fn set_file_template(&mut self, template: &str) {
+        template.clone_into(&mut self.file_template);
+    }
+
+    /// Compresses every finished file to FLAC with `encoder`.
+    pub fn set_flac(&mut self, encoder: FlacEncoder) {
+        self.flac = Some(encoder);
+    }
+
+    /// Stops starting new files while `low_space` is set, capture itself keeps running.
+    pub fn set_low_space(&mut self, low_space: &'a AtomicBool) {
+        self.low_space = Some(low_space);
+    }
+
+    /// Finalizes the current file and starts a new one whenever `checkpoint` is incremented.
+    pub fn set_checkpoint(&mut self, checkpoint: &'a AtomicUsize) {
+        self.checkpoint = Some(checkpoint);
+    }
+
+    /// Raises the peak absolute sample of each channel in `peaks`, which the reader resets
+    /// after reporting them.
+    pub fn set_peaks(&mut self, peaks: &'a [AtomicI32]) {
+        self.peaks = Some(peaks);
     }
 
-    fn init_device(&self) -> Result<PCM, Error> {
-        let pcm = PCM::new(&self.device_name, Direction::Capture, false)?;
+    #[cfg(feature = "mock-hardware")]
+    fn init_device(&self) -> Result<(PCM, Negotiated), Error> {
+        let negotiated = Negotiated {
+            device_name: self.device_names.first().cloned().unwrap_or_default(),
+            channels: self.channels,
+            samplerate: self.samplerate,
+            format: self.format,
+        };
+        Ok((PCM::new(self.channels, self.samplerate, self.format), negotiated))
+    }
+
+    /// Opens the first candidate device name that works.
+    #[cfg(not(feature = "mock-hardware"))]
+    fn open(&self) -> Result<(PCM, &str), Error> {
+        let mut last_err = Error::unsupported("snd_pcm_open");
+        for (i, name) in self.device_names.iter().enumerate() {
+            match PCM::new(name, Direction::Capture, false) {
+                Ok(pcm) => {
+                    if i > 0 {
+                        info!("Opened fallback capture device {name}");
+                    }
+                    return Ok((pcm, name));
+                }
+                Err(err) => last_err = err,
+            }
+        }
+        Err(last_err)
+    }
+
+    #[cfg(not(feature = "mock-hardware"))]
+    fn init_device(&self) -> Result<(PCM, Negotiated), Error> {
+        let (pcm, device_name) = self.open()?;
         {
             let hwp = HwParams::any(&pcm)?;
             hwp.set_channels(self.channels)?;
@@ -70,35 +333,113 @@ impl<'a> CaptureDevice<'a> {
             hwp.set_access(Access::RWInterleaved)?;
             pcm.hw_params(&hwp)?;
         }
+        let negotiated = {
+            let hwp = pcm.hw_params_current()?;
+            Negotiated {
+                device_name: device_name.to_owned(),
+                channels: hwp.get_channels()?,
+                samplerate: hwp.get_rate()?,
+                format: hwp.get_format()?,
+            }
+        };
+        if negotiated.channels != self.channels
+            || negotiated.samplerate != self.samplerate
+            || negotiated.format != self.format
+        {
+            warn!(
+                "{} granted {} channels at {} Hz in {}, requested {} channels at {} Hz in {}",
+                negotiated.device_name,
+                negotiated.channels,
+                negotiated.samplerate,
+                negotiated.format,
+                self.channels,
+                self.samplerate,
+                self.format
+            );
+        }
         pcm.prepare()?;
         pcm.start()?;
-        Ok(pcm)
+        Ok((pcm, negotiated))
+    }
+
+    /// Opens the device and performs a single read, discarding the samples.
+    pub fn probe(&self) -> Result<(), CaptureDeviceError> {
+        let (pcm, negotiated) = self.init_device()?;
+        let mut buf = vec![0i32; Self::block_len(negotiated.channels)];
+        SampleReader::new(&pcm, negotiated.format, buf.len())?.readi(&mut buf)?;
+        Ok(())
+    }
+
+    /// Returns the number of samples in a block of whole frames of `channels` samples.
+    fn block_len(channels: u32) -> usize {
+        let channels = channels.max(1) as usize;
+        (Self::BLOCK_SAMPLES / channels).max(1) * channels
+    }
+
+    fn create_file(&self, spec: WavSpec) -> Result<AudioFile, CaptureDeviceError> {
+        let now = chrono::Utc::now();
+        let nanos = now.timestamp_nanos_opt().unwrap();
+        let name = self
+            .file_template
+            .replace("{nanos}", &nanos.to_string())
+            .replace("{time}", &now.format("%Y-%m-%dT%H-%M-%S%.3fZ").to_string());
+        // a reopen within the resolution of the template must not overwrite the previous file
+        let mut path = self.output_dir.join(format!("{name}.wav"));
+        let mut n = 1;
+        while path.exists() || AudioFile::partial_path(&path).exists() {
+            path = self.output_dir.join(format!("{name}_{n}.wav"));
+            n += 1;
+        }
+        AudioFile::create(path, spec)
+    }
+
+    fn finish_file(&self, file: AudioFile) -> Result<(), CaptureDeviceError> {
+        let path = file.finalize()?;
+        if let Some(encoder) = &self.flac {
+            encoder.push(path);
+        }
+        Ok(())
+    }
+
+    /// Finalizes `file` and starts the next one, unless storage is running out.
+    fn next_file(
+        &self,
+        file: Option<AudioFile>,
+        spec: WavSpec,
+    ) -> Result<Option<AudioFile>, CaptureDeviceError> {
+        if let Some(file) = file {
+            self.finish_file(file)?;
+        }
+        if self.low_space.is_some_and(|low| low.load(Ordering::Relaxed)) {
+            return Ok(None);
+        }
+        self.create_file(spec).map(Some)
     }
 
     pub fn read(&self, file_duration: Duration) -> Result<(), CaptureDeviceError> {
         #[allow(clippy::cast_possible_wrap)]
         const PREFIX: i32 = 0xeeee_eeeeu32 as i32;
 
-        let pcm = self.init_device()?;
-        let io = match &self.format {
-            Format::S32LE | Format::S32BE => pcm.io_i32()?,
-            default => return Err(CaptureDeviceError::FormatUnimplemented(*default)),
-        };
+        let (pcm, negotiated) = self.init_device()?;
+        let mut buf = vec![0i32; Self::block_len(negotiated.channels)];
+        let mut io = SampleReader::new(&pcm, negotiated.format, buf.len())?;
 
-        let mut buf = [0i32; 1024];
         let wav_spec = hound::WavSpec {
             #[allow(clippy::cast_possible_truncation)]
-            channels: self.channels as u16,
-            sample_rate: self.samplerate,
+            channels: negotiated.channels as u16,
+            sample_rate: negotiated.samplerate,
             bits_per_sample: 32,
             sample_format: SampleFormat::Int,
         };
 
-        let mut nanos = chrono::Utc::now().timestamp_nanos_opt().unwrap();
-        let mut path = self.output_dir.join(format!("{nanos}.wav"));
-        let mut writer = WavWriter::create(path, wav_spec)?;
+        for _ in 0..self.warmup_blocks {
+            io.readi(&mut buf)?;
+        }
+
+        let mut file = self.next_file(None, wav_spec)?;
         let mut start = Instant::now();
         let mut last_read = Instant::now();
+        let mut checkpoint = self.checkpoint.map(|c| c.load(Ordering::Relaxed));
         while self.running.load(Ordering::Relaxed) {
             {
                 let mut pps = self.pps.lock();
@@ -107,10 +448,12 @@ impl<'a> CaptureDevice<'a> {
                     let low: i32 = (pps.1 & 0xffff_ffff) as i32;
                     let high: i32 = (pps.1 >> 32) as i32;
                     drop(pps);
-                    writer.write_sample(PREFIX)?;
-                    writer.write_sample(PREFIX)?;
-                    writer.write_sample(high)?;
-                    writer.write_sample(low)?;
+                    if let Some(file) = file.as_mut() {
+                        file.writer.write_sample(PREFIX)?;
+                        file.writer.write_sample(PREFIX)?;
+                        file.writer.write_sample(high)?;
+                        file.writer.write_sample(low)?;
+                    }
                 }
             }
             //if let Ok(s) = io.readi(&mut buf) {
@@ -128,29 +471,107 @@ impl<'a> CaptureDevice<'a> {
             //}
             if io.readi(&mut buf)? * wav_spec.channels as usize == buf.len() {
                 let mut zeros = 0;
-                let mut samples = buf.len();
-                for sample in buf {
+                let samples = buf.len();
+                for &sample in &buf {
                     if sample.trailing_zeros() >= 28 || sample.leading_zeros() >= 28 {
                         zeros += 1;
                     }
-                    writer.write_sample(sample)?;
+                    if let Some(file) = file.as_mut() {
+                        file.writer.write_sample(sample)?;
+                    }
                 }
                 if zeros < samples {
                     last_read = Instant::now();
                 }
+                if let Some(peaks) = self.peaks {
+                    for frame in buf.chunks_exact(wav_spec.channels as usize) {
+                        for (peak, sample) in peaks.iter().zip(frame) {
+                            peak.fetch_max(sample.saturating_abs(), Ordering::Relaxed);
+                        }
+                    }
+                }
+            }
+            let requested = self.checkpoint.map(|c| c.load(Ordering::Relaxed));
+            if requested != checkpoint {
+                checkpoint = requested;
+                file = self.next_file(file, wav_spec)?;
+                info!("{} checkpoint, started a new file", negotiated.device_name);
             }
-            if start.elapsed() >= file_duration {
-                start = start.checked_add(file_duration).unwrap();
-                writer.finalize()?;
-                nanos = chrono::Utc::now().timestamp_nanos_opt().unwrap();
-                path = self.output_dir.join(format!("{nanos}.wav"));
-                writer = WavWriter::create(path, wav_spec)?;
+            let elapsed = start.elapsed();
+            if elapsed >= file_duration {
+                // Advance by every whole interval that passed, so file boundaries catch up with
+                // real time after a stall instead of lagging behind it
+                #[allow(clippy::cast_possible_truncation)]
+                let intervals = (elapsed.as_nanos() / file_duration.as_nanos().max(1)) as u32;
+                start = start
+                    .checked_add(file_duration.saturating_mul(intervals))
+                    .unwrap_or_else(Instant::now);
+                file = self.next_file(file, wav_spec)?;
             }
             if last_read.elapsed().as_secs() >= 2 {
                 self.status.store(1, Ordering::Relaxed);
             }
+            // Some USB devices stop streaming without ever returning an error, returning lets the
+            // caller drop the PCM handle and open the device again
+            if last_read.elapsed() >= self.watchdog {
+                if let Some(file) = file {
+                    self.finish_file(file)?;
+                }
+                return Err(CaptureDeviceError::Stalled(self.watchdog));
+            }
         }
 
-        Ok(())
+        match file {
+            Some(file) => self.finish_file(file),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks_hold_whole_frames() {
+        assert_eq!(CaptureDevice::block_len(2), 1024);
+        assert_eq!(CaptureDevice::block_len(8), 1024);
+        assert_eq!(CaptureDevice::block_len(3), 1023);
+        assert_eq!(CaptureDevice::block_len(6), 1020);
+        assert_eq!(CaptureDevice::block_len(2000), 2000);
+    }
+
+    #[test]
+    fn same_second_files_dont_overwrite() {
+        let dir = tempfile::tempdir().unwrap();
+        let (running, status) = (AtomicBool::new(true), AtomicU8::new(0));
+        let pps = Arc::new(Mutex::new((false, 0)));
+        let mut capture = CaptureDevice::new(
+            &[],
+            2,
+            48000,
+            Format::S32LE,
+            dir.path(),
+            &running,
+            &status,
+            pps,
+        );
+        capture.set_file_template("{time}");
+        let spec = WavSpec {
+            channels: 2,
+            sample_rate: 48000,
+            bits_per_sample: 32,
+            sample_format: SampleFormat::Int,
+        };
+        // back to back, the way a checkpoint or reopen rotates files, all within one second
+        let start = Instant::now();
+        let paths: Vec<_> = (0..3)
+            .map(|_| capture.create_file(spec).unwrap().finalize().unwrap())
+            .collect();
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_ne!(paths[0], paths[1]);
+        assert_ne!(paths[1], paths[2]);
+        assert_ne!(paths[0], paths[2]);
+        assert!(paths.iter().all(|path| path.exists()));
     }
 }
@@ -0,0 +1,523 @@
+use std::path::Path;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::data::device_manager::Settings;
+use crate::data::ina;
+use crate::data::mqtt::MqttSettings;
+use crate::data::ndjson::NdjsonSettings;
+
+/// Contents of `config.toml` in the andros directory. Every key is optional, missing ones keep
+/// the built-in defaults, e.g.
+///
+/// ```toml
+/// audio_file_template = "{device}_{mac}_{time}"
+///
+/// [i2s]
+/// file_duration = 5
+/// watchdog = 30
+///
+/// [umc]
+/// devices = ["hw:CARD=U192k,DEV=0"]
+/// samplerate = 96000
+/// format = "S24_3LE"
+/// flac = true
+///
+/// [scheduling]
+/// fifo_priority = 50
+/// cpu = 3
+///
+/// [sensors]
+/// gps_port = "/dev/ttyAMA0"
+/// imu_bus = 3
+/// ina_shunt_uohm = 100000
+/// wind_integer_speed = true
+/// wind_speed_factor = 0.1
+/// imu_period_ms = 20
+///
+/// [gps]
+/// fixed_location = { lat = 52.4064, lon = 16.9252, alt = 70.0 }
+/// outlier_distance = 200.0
+///
+/// [data]
+/// ndjson = true
+/// csv_export = true
+/// files_kept = 1000
+///
+/// [health]
+/// min_free_gb = 2.0
+///
+/// [endpoints]
+/// publish_url = "http://192.168.1.10:8080/andros/publish"
+/// status_port = 8080
+///
+/// [mqtt]
+/// broker = "192.168.1.10"
+/// username = "andros"
+/// password = "secret"
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigFile {
+    /// See `Config::audio_file_template`
+    pub audio_file_template: Option<String>,
+    pub i2s: CaptureConfig,
+    pub umc: CaptureConfig,
+    /// Applies to both capture threads
+    pub scheduling: SchedulingConfig,
+    pub sensors: SensorConfig,
+    pub endpoints: EndpointConfig,
+    pub gps: GpsConfig,
+    pub data: DataConfig,
+    pub health: HealthConfig,
+    /// Publishing to MQTT is enabled by this section
+    pub mqtt: Option<MqttConfig>,
+}
+
+impl ConfigFile {
+    pub const NAME: &'static str = "config.toml";
+
+    /// Returns `None` if the file doesn't exist.
+    pub fn load(path: &Path) -> Result<Option<Self>, String> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.to_string()),
+        };
+        toml::from_str(&text).map(Some).map_err(|err| err.to_string())
+    }
+}
+
+/// Checks that an audio file name template only uses known placeholders and gives every file a
+/// unique name.
+pub fn validate_file_template(template: &str) -> Result<(), String> {
+    const PLACEHOLDERS: [&str; 4] = ["device", "mac", "nanos", "time"];
+    if template.contains(['/', '\\']) {
+        return Err(format!("audio file template {template:?} contains a path separator"));
+    }
+    let mut timestamped = false;
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let Some(len) = rest[start..].find('}') else {
+            return Err(format!("unclosed placeholder in audio file template {template:?}"));
+        };
+        let name = &rest[start + 1..start + len];
+        if !PLACEHOLDERS.contains(&name) {
+            return Err(format!("unknown placeholder {{{name}}} in audio file template"));
+        }
+        timestamped |= name == "nanos" || name == "time";
+        rest = &rest[start + len + 1..];
+    }
+    if rest.contains('}') || !timestamped {
+        return Err(format!(
+            "audio file template {template:?} needs a {{nanos}} or {{time}} placeholder"
+        ));
+    }
+    Ok(())
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CaptureConfig {
+    /// ALSA names tried in order
+    pub devices: Option<Vec<String>>,
+    pub channels: Option<u32>,
+    pub samplerate: Option<u32>,
+    /// ALSA format name, e.g. `S32_LE`
+    pub format: Option<String>,
+    /// Compress finished recordings to FLAC
+    pub flac: Option<bool>,
+    /// Length of each audio file in seconds
+    pub file_duration: Option<u64>,
+    /// Seconds of silence after which the device is reinitialized
+    pub watchdog: Option<u64>,
+    /// Blocks of about 1024 samples discarded after opening the device, while the ADC settles
+    pub warmup_blocks: Option<usize>,
+}
+
+/// Scheduling of the capture threads, which suffer dropouts when competing for the CPU
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SchedulingConfig {
+    /// `SCHED_FIFO` priority, 1 to 99
+    pub fifo_priority: Option<i32>,
+    /// Core the capture threads are pinned to, below the number of cores
+    pub cpu: Option<usize>,
+}
+
+/// Sensor buses, ports and tuning, overriding `Settings::default`
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SensorConfig {
+    pub gps_port: Option<String>,
+    pub gps_baud_rate: Option<u32>,
+    pub wind_port: Option<String>,
+    pub wind_baud_rate: Option<u32>,
+    pub aht_bus: Option<u8>,
+    pub imu_bus: Option<u8>,
+    /// Shunt resistance in micro-ohms, the INA219 runs uncalibrated without it
+    pub ina_shunt_uohm: Option<u32>,
+    /// Largest expected current in milliamperes, defaults to the full 320 mV shunt range
+    pub ina_max_current_ma: Option<u32>,
+    /// See `WindScaling`
+    pub wind_integer_speed: Option<bool>,
+    pub wind_speed_factor: Option<f32>,
+    pub wind_speed_offset: Option<f32>,
+    pub wind_dir_factor: Option<f32>,
+    /// Number of pressure samples the altitude is median-filtered over
+    pub bmp_window: Option<usize>,
+    /// See `Settings::bmp_gps_reference`
+    pub bmp_gps_reference: Option<bool>,
+    pub imu_period_ms: Option<u64>,
+    /// Span of the gyroscope calibration and rotation window
+    pub imu_window_secs: Option<u64>,
+    /// Degrees of rotation within the window that trigger a magnetometer recalibration, see
+    /// `ImuSettings::recal_rotation`
+    pub imu_recal_rotation: Option<f32>,
+    /// Consecutive failed reads after which a sensor is initialized again
+    pub reinit_after: Option<usize>,
+}
+
+impl SensorConfig {
+    pub fn apply(&self, settings: &mut Settings) {
+        if let Some(port) = &self.gps_port {
+            port.clone_into(&mut settings.gps.uart.port);
+        }
+        if let Some(baud_rate) = self.gps_baud_rate {
+            settings.gps.uart.baud_rate = baud_rate;
+        }
+        if let Some(port) = &self.wind_port {
+            port.clone_into(&mut settings.wind.uart.port);
+        }
+        if let Some(baud_rate) = self.wind_baud_rate {
+            settings.wind.uart.baud_rate = baud_rate;
+        }
+        if let Some(bus) = self.aht_bus {
+            settings.aht_bus = bus;
+        }
+        if let Some(bus) = self.imu_bus {
+            settings.imu_bus = bus;
+        }
+        if let Some(shunt_uohm) = self.ina_shunt_uohm.filter(|&r| r > 0) {
+            let max_current_ma = self.ina_max_current_ma.unwrap_or(320_000_000 / shunt_uohm);
+            settings.ina_calibration = Some(ina::Calibration {
+                shunt_uohm,
+                max_current_ma,
+            });
+        }
+        let scaling = &mut settings.wind.scaling;
+        if let Some(integer_speed) = self.wind_integer_speed {
+            scaling.integer_speed = integer_speed;
+        }
+        if let Some(factor) = self.wind_speed_factor {
+            scaling.speed_factor = factor;
+        }
+        if let Some(offset) = self.wind_speed_offset {
+            scaling.speed_offset = offset;
+        }
+        if let Some(factor) = self.wind_dir_factor {
+            scaling.dir_factor = factor;
+        }
+        if let Some(window) = self.bmp_window {
+            settings.bmp.window = window;
+        }
+        if let Some(gps_reference) = self.bmp_gps_reference {
+            settings.bmp_gps_reference = gps_reference;
+        }
+        if let Some(millis) = self.imu_period_ms {
+            settings.imu_period = Duration::from_millis(millis);
+        }
+        if let Some(secs) = self.imu_window_secs {
+            settings.imu_window = Duration::from_secs(secs);
+        }
+        if let Some(degrees) = self.imu_recal_rotation {
+            settings.imu_recal_rotation = degrees;
+        }
+        if let Some(reads) = self.reinit_after {
+            settings.reinit_after = reads;
+        }
+    }
+}
+
+/// Network endpoints, overriding `Settings::default`
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EndpointConfig {
+    /// Post data to `publish_url`, otherwise it's only written locally
+    pub publish: Option<bool>,
+    /// URL data is posted to every cycle
+    pub publish_url: Option<String>,
+    /// Number of messages waiting to be published before the oldest ones are dropped
+    pub publish_queue: Option<usize>,
+    pub publish_connect_timeout_secs: Option<u64>,
+    /// Limit on a whole publish request
+    pub publish_timeout_secs: Option<u64>,
+    /// Size of the spool keeping messages while the endpoint is unreachable, 0 disables it
+    pub publish_spool_bytes: Option<u64>,
+    /// Port serving the latest statuses on `GET /status`
+    pub status_port: Option<u16>,
+}
+
+impl EndpointConfig {
+    pub fn apply(&self, settings: &mut Settings) {
+        if let Some(publish) = self.publish {
+            settings.publish = publish;
+        }
+        if let Some(url) = &self.publish_url {
+            url.clone_into(&mut settings.publish_url);
+        }
+        if let Some(queue) = self.publish_queue {
+            settings.publish_queue = queue;
+        }
+        if let Some(secs) = self.publish_connect_timeout_secs {
+            settings.publish_connect_timeout = Duration::from_secs(secs);
+        }
+        if let Some(secs) = self.publish_timeout_secs {
+            settings.publish_timeout = Duration::from_secs(secs);
+        }
+        if let Some(bytes) = self.publish_spool_bytes {
+            settings.publish_spool_bytes = (bytes > 0).then_some(bytes);
+        }
+        if let Some(port) = self.status_port {
+            settings.status_port = Some(port);
+        }
+    }
+}
+
+/// GPS behaviour, overriding `Settings::default`
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GpsConfig {
+    /// Surveyed-in position reported while the GPS has no fix
+    pub fixed_location: Option<Location>,
+    /// See `OutlierGate`
+    pub outlier_distance: Option<f64>,
+    pub outlier_limit: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Location {
+    /// Degrees north
+    pub lat: f64,
+    /// Degrees east
+    pub lon: f64,
+    /// Meters above sea level
+    pub alt: f32,
+}
+
+impl GpsConfig {
+    pub fn apply(&self, settings: &mut Settings) {
+        if let Some(location) = self.fixed_location {
+            settings.fixed_location = Some((location.lat, location.lon, location.alt));
+        }
+        if let Some(distance) = self.outlier_distance {
+            settings.gps.outlier.distance = distance;
+        }
+        if let Some(limit) = self.outlier_limit {
+            settings.gps.outlier.limit = limit;
+        }
+    }
+}
+
+/// Thresholds of the health statuses, overriding `Settings::default`
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthConfig {
+    /// Number of cycles the reported temperature is averaged over
+    pub temp_window: Option<usize>,
+    /// Consecutive failed data file writes after which storage is reported as failing
+    pub storage_failures: Option<u32>,
+    /// Free space in GiB below which no new data or audio files are started
+    pub min_free_gb: Option<f32>,
+    /// Seconds both capture paths have to report no data before an audio fault is raised
+    pub audio_fault_after_secs: Option<u64>,
+}
+
+impl HealthConfig {
+    pub fn apply(&self, settings: &mut Settings) {
+        if let Some(window) = self.temp_window {
+            settings.temp_window = window;
+        }
+        if let Some(failures) = self.storage_failures {
+            settings.storage_failures = failures;
+        }
+        if let Some(gb) = self.min_free_gb {
+            settings.min_free_gb = gb;
+        }
+        if let Some(secs) = self.audio_fault_after_secs {
+            settings.audio_fault_after = Duration::from_secs(secs);
+        }
+    }
+}
+
+/// Sensor data files, overriding `Settings::default`
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DataConfig {
+    /// Append cycles to rolling ndjson files instead of writing a file per cycle
+    pub ndjson: Option<bool>,
+    /// Size in bytes after which a new ndjson file is started
+    pub ndjson_max_bytes: Option<u64>,
+    /// Age in seconds after which a new ndjson file is started
+    pub ndjson_max_age_secs: Option<u64>,
+    /// Also append every cycle to `sensors.csv`
+    pub csv_export: Option<bool>,
+    /// Number of newest data files kept, older ones are deleted; all are kept when unset
+    pub files_kept: Option<usize>,
+}
+
+impl DataConfig {
+    pub fn apply(&self, settings: &mut Settings) {
+        if let Some(ndjson) = self.ndjson {
+            settings.ndjson = ndjson.then(NdjsonSettings::default);
+        }
+        if let Some(ndjson) = settings.ndjson.as_mut() {
+            if let Some(max_bytes) = self.ndjson_max_bytes {
+                ndjson.max_bytes = max_bytes;
+            }
+            if let Some(secs) = self.ndjson_max_age_secs {
+                ndjson.max_age = Duration::from_secs(secs);
+            }
+        }
+        if let Some(csv_export) = self.csv_export {
+            settings.csv_export = csv_export;
+        }
+        if let Some(kept) = self.files_kept {
+            settings.data_files_kept = Some(kept);
+        }
+    }
+}
+
+/// MQTT broker every cycle is also published to
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MqttConfig {
+    /// Host name or address
+    pub broker: String,
+    pub port: Option<u16>,
+    /// Defaults to `andros-{mac}`, brokers drop the older connection of two with the same id
+    pub client_id: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Defaults to `andros/{client_id}/telemetry`
+    pub telemetry_topic: Option<String>,
+    /// Number of messages waiting to be sent before new ones are dropped
+    pub queue: Option<usize>,
+}
+
+impl MqttConfig {
+    const DEFAULT_PORT: u16 = 1883;
+    const DEFAULT_QUEUE: usize = 12;
+
+    /// Fills in the defaults, `mac` is the station identifier used in the client id.
+    pub fn settings(self, mac: Option<&str>) -> Result<MqttSettings, String> {
+        let credentials = match (self.username, self.password) {
+            (Some(user), Some(password)) => Some((user, password)),
+            (None, None) => None,
+            _ => return Err("mqtt username and password must be set together".to_owned()),
+        };
+        let client_id = self.client_id.unwrap_or_else(|| match mac {
+            Some(mac) => format!("andros-{}", mac.replace(':', "-")),
+            None => "andros".to_owned(),
+        });
+        let telemetry_topic =
+            self.telemetry_topic.unwrap_or_else(|| format!("andros/{client_id}/telemetry"));
+        Ok(MqttSettings {
+            host: self.broker,
+            port: self.port.unwrap_or(Self::DEFAULT_PORT),
+            client_id,
+            credentials,
+            telemetry_topic,
+            queue: self.queue.unwrap_or(Self::DEFAULT_QUEUE),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixed_location() {
+        let file: ConfigFile =
+            toml::from_str("[gps]\nfixed_location = { lat = 52.5, lon = 16.5, alt = 70.0 }")
+                .unwrap();
+        let mut settings = Settings::default();
+        file.gps.apply(&mut settings);
+        assert_eq!(settings.fixed_location, Some((52.5, 16.5, 70.0)));
+        assert!(toml::from_str::<ConfigFile>("[gps]\nfixed_location = { lat = 52.5 }").is_err());
+    }
+
+    #[test]
+    fn publish_toggle() {
+        let file: ConfigFile = toml::from_str("[endpoints]\npublish = false").unwrap();
+        let mut settings = Settings::default();
+        file.endpoints.apply(&mut settings);
+        assert!(!settings.publish);
+        assert_eq!(settings.publish_url, Settings::default().publish_url);
+    }
+
+    #[test]
+    fn file_template() {
+        assert!(validate_file_template("{nanos}").is_ok());
+        assert!(validate_file_template("{device}_{mac}_{time}").is_ok());
+        assert!(validate_file_template("{device}_{mac}").is_err());
+        assert!(validate_file_template("{device}_{date}_{time}").is_err());
+        assert!(validate_file_template("{device}_{time").is_err());
+        assert!(validate_file_template("audio/{nanos}").is_err());
+    }
+
+    #[test]
+    fn tunables() {
+        let file: ConfigFile = toml::from_str(
+            "[sensors]\nwind_speed_factor = 0.1\nimu_period_ms = 20\nreinit_after = 3\n\
+             [gps]\noutlier_limit = 2\n\
+             [health]\nmin_free_gb = 2.5\n\
+             [endpoints]\npublish_timeout_secs = 9\npublish_spool_bytes = 0",
+        )
+        .unwrap();
+        let mut settings = Settings::default();
+        file.sensors.apply(&mut settings);
+        file.gps.apply(&mut settings);
+        file.health.apply(&mut settings);
+        file.endpoints.apply(&mut settings);
+        assert!((settings.wind.scaling.speed_factor - 0.1).abs() < f32::EPSILON);
+        assert_eq!(settings.imu_period, Duration::from_millis(20));
+        assert_eq!(settings.reinit_after, 3);
+        assert_eq!(settings.gps.outlier.limit, 2);
+        assert!((settings.min_free_gb - 2.5).abs() < f32::EPSILON);
+        assert_eq!(settings.publish_timeout, Duration::from_secs(9));
+        assert!(settings.publish_spool_bytes.is_none());
+    }
+
+    #[test]
+    fn ndjson() {
+        let file: ConfigFile =
+            toml::from_str("[data]\nndjson = true\nndjson_max_age_secs = 600").unwrap();
+        let mut settings = Settings::default();
+        file.data.apply(&mut settings);
+        let ndjson = settings.ndjson.unwrap();
+        assert_eq!(ndjson.max_age, Duration::from_secs(600));
+        assert_eq!(ndjson.max_bytes, NdjsonSettings::default().max_bytes);
+    }
+
+    #[test]
+    fn mqtt_defaults() {
+        let file: ConfigFile = toml::from_str("[mqtt]\nbroker = \"10.0.0.1\"").unwrap();
+        let settings = file.mqtt.unwrap().settings(Some("AA:BB")).unwrap();
+        assert_eq!(settings.host, "10.0.0.1");
+        assert_eq!(settings.port, 1883);
+        assert_eq!(settings.client_id, "andros-AA-BB");
+        assert_eq!(settings.telemetry_topic, "andros/andros-AA-BB/telemetry");
+        assert!(settings.credentials.is_none());
+    }
+
+    #[test]
+    fn mqtt_credentials_need_both() {
+        let file: ConfigFile =
+            toml::from_str("[mqtt]\nbroker = \"10.0.0.1\"\nusername = \"andros\"").unwrap();
+        assert!(file.mqtt.unwrap().settings(None).is_err());
+    }
+}
@@ -1,45 +1,191 @@
 use parking_lot::Mutex;
+use std::fmt::Display;
 use std::fs::File;
 use std::io::BufWriter;
 use std::io::Write;
 use std::path::Path;
 use std::path::PathBuf;
 use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicI32;
 use std::sync::atomic::AtomicU8;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::thread;
 use std::thread::Scope;
 use std::time::{Duration, Instant};
+use sysinfo::Component;
+use sysinfo::Components;
 use sysinfo::DiskRefreshKind;
 use sysinfo::Disks;
 
 use ::serde::{Deserialize, Serialize};
-use log::{error, info, warn};
+use log::{debug, error, info, warn};
 
 use self::aht::Aht;
 use self::bmp::Bmp;
-use self::device_manager::{DeviceManager, Status, Statuses};
+use self::circular_buffer::CircularVector;
+use self::csv_export::CsvExport;
+use self::device_manager::{DeviceManager, ImuSettings, Status, Statuses};
 use self::gps::Gps;
 use self::imu::Imu;
+use self::mqtt::MqttPublisher;
+use self::ndjson::NdjsonLog;
+use self::publisher::Publisher;
+use self::spool::Spool;
 use self::wind::Wind;
 
 pub mod aht;
 pub mod bmp;
+mod circular_buffer;
+mod csv_export;
 pub mod device_manager;
 pub mod gps;
 pub mod imu;
 pub mod ina;
+pub mod mqtt;
+pub mod ndjson;
+mod publisher;
+mod spool;
+mod status_server;
 pub mod wind;
 
-pub trait Device {
+pub trait Device: Sized {
     type Data;
-    type Error;
+    type Error: Display;
+    type Settings;
 
+    fn init(settings: &Self::Settings) -> Result<Self, Self::Error>;
     fn get_data(&mut self) -> Result<Self::Data, Self::Error>;
+
+    /// Describes the first physically impossible value in `data`, such readings come from
+    /// corrupt frames and are dropped.
+    fn implausible(_data: &Self::Data) -> Option<String> {
+        None
+    }
+
+    /// Whether `err` only means no new reading is available yet. Such reads aren't counted as
+    /// failures, polling faster than the device measures must not get it reinitialized.
+    fn not_ready(_err: &Self::Error) -> bool {
+        false
+    }
+}
+
+/// Describes `value` if it's not a finite number within `range`.
+fn out_of_range<T>(name: &str, value: T, range: std::ops::RangeInclusive<T>) -> Option<String>
+where
+    T: PartialOrd + Display,
+{
+    (!range.contains(&value))
+        .then(|| format!("{name} {value} outside {}..={}", range.start(), range.end()))
+}
+
+/// A lazily initialized device. Failed initializations are retried with an increasing delay,
+/// so an absent device doesn't spam the log every cycle.
+pub struct DeviceSlot<D> {
+    device: Option<D>,
+    failures: usize,
+    retry_at: Instant,
+    /// Consecutive failed reads
+    read_failures: usize,
+    reinit_after: Option<usize>,
+}
+
+impl<D> Default for DeviceSlot<D> {
+    fn default() -> Self {
+        Self {
+            device: None,
+            failures: 0,
+            retry_at: Instant::now(),
+            read_failures: 0,
+            reinit_after: None,
+        }
+    }
 }
 
-#[derive(Debug, Default, Serialize, Deserialize)]
+impl<D: Device> DeviceSlot<D> {
+    pub fn device_mut(&mut self) -> Option<&mut D> {
+        self.device.as_mut()
+    }
+
+    /// Drops the device after `reads` consecutive failed reads so it's initialized again, a
+    /// device that fell off the bus on a power glitch otherwise never recovers.
+    pub fn set_reinit_after(&mut self, reads: usize) {
+        self.reinit_after = Some(reads.max(1));
+    }
+
+    fn read_failed(&mut self, name: &str) {
+        self.read_failures += 1;
+        if self.reinit_after.is_some_and(|after| self.read_failures >= after) {
+            warn!("{name} failed {} reads in a row, reinitializing", self.read_failures);
+            self.device = None;
+            self.read_failures = 0;
+        }
+    }
+
+    /// Delays before retrying initialization, the last one repeats indefinitely
+    const BACKOFF_SECS: [u64; 6] = [5, 10, 30, 60, 120, 300];
+    /// Failed initializations logged as warnings before dropping to debug level
+    const LOUD_FAILURES: usize = 3;
+
+    /// Reads from the device, initializing it first if it isn't yet, and updates `status` to
+    /// match.
+    ///
+    /// A freshly initialized device reports `NoData` until its first successful read.
+    pub fn poll(&mut self, settings: &D::Settings, status: &mut Status, name: &str) -> Option<D::Data> {
+        if let Some(device) = self.device.as_mut() {
+            return match device.get_data() {
+                Ok(data) => {
+                    if let Some(reason) = D::implausible(&data) {
+                        warn!("{name} rejected reading: {reason}");
+                        *status = Status::NoData;
+                        self.read_failed(name);
+                        return None;
+                    }
+                    self.read_failures = 0;
+                    *status = Status::Ok;
+                    Some(data)
+                }
+                Err(err) if D::not_ready(&err) => {
+                    debug!("{name} {err}");
+                    *status = Status::NoData;
+                    None
+                }
+                Err(err) => {
+                    warn!("{name} data error: {err}");
+                    *status = Status::NoData;
+                    self.read_failed(name);
+                    None
+                }
+            };
+        }
+
+        if Instant::now() < self.retry_at {
+            return None;
+        }
+        match D::init(settings) {
+            Ok(d) => {
+                info!("{name} device initialized");
+                self.device = Some(d);
+                self.failures = 0;
+                *status = Status::NoData;
+            }
+            Err(err) => {
+                let backoff = Self::BACKOFF_SECS[self.failures.min(Self::BACKOFF_SECS.len() - 1)];
+                self.failures += 1;
+                self.retry_at = Instant::now() + Duration::from_secs(backoff);
+                if self.failures <= Self::LOUD_FAILURES {
+                    warn!("{name} init failed: {err}, retrying in {backoff} s");
+                } else {
+                    debug!("{name} init failed: {err}, retrying in {backoff} s");
+                }
+                *status = Status::Disconnected;
+            }
+        }
+        None
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct Data {
     gps: Option<gps::Data>,
     aht: Option<aht::Data>,
@@ -49,6 +195,44 @@ pub struct Data {
     ina: Option<ina::Data>,
 }
 
+/// Statuses and data of the latest read cycle, as written to the data files and published.
+///
+/// This is the wire format consumers ingest, serialized as
+/// `{"statuses": {..}, "data": {"gps", "aht", "wind", "imu", "bmp", "ina"}}` with every sensor
+/// `null` when it produced no data in the cycle. Field names of `Statuses` and of the sensor
+/// `Data` types are part of the format, renaming one breaks ingestion.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub statuses: Statuses,
+    pub data: Data,
+}
+
+/// Deletes all but the newest `keep` data files in `dir`, both the per-cycle `{nanos}.json`
+/// files and the rolling `{nanos}.ndjson` ones, which are named by their creation time. The
+/// newest file is always kept, it's the one being written.
+fn prune_data_files(dir: &Path, keep: usize) -> std::io::Result<()> {
+    let keep = keep.max(1);
+    let mut files: Vec<(u64, PathBuf)> = std::fs::read_dir(dir)?
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json" || ext == "ndjson"))
+        .filter_map(|path| {
+            let nanos = path.file_stem()?.to_str()?.parse().ok()?;
+            Some((nanos, path))
+        })
+        .collect();
+    if files.len() <= keep {
+        return Ok(());
+    }
+    files.sort_unstable_by_key(|&(nanos, _)| nanos);
+    let excess = files.len() - keep;
+    for (_, path) in files.into_iter().take(excess) {
+        std::fs::remove_file(path)?;
+    }
+    info!("Removed {excess} old data files");
+    Ok(())
+}
+
 pub struct Reader<'a> {
     pub device_manager: DeviceManager,
     pub path: PathBuf,
@@ -57,16 +241,25 @@ pub struct Reader<'a> {
     pub read_period: Duration,
     i2s_status: &'a AtomicU8,
     umc_status: &'a AtomicU8,
+    i2s_peaks: &'a [AtomicI32],
+    umc_peaks: &'a [AtomicI32],
+    snapshot: Arc<Mutex<Snapshot>>,
+    low_space: Option<&'a AtomicBool>,
 }
 
 impl<'a> Reader<'a> {
     const PERIOD_MILLIS: u64 = 5000;
+    /// Read cycles between removals of old data files
+    const PRUNE_CYCLES: u32 = 60;
+    /// Weight of each GPS derived reference in the BMP280 ground pressure, smoothing out the
+    /// noise of the GPS altitude
+    const GROUND_PRESSURE_WEIGHT: f32 = 0.1;
 
     pub fn new<P: Into<PathBuf>>(
         path: P,
         calib_path: &'a PathBuf,
-        i2s_status: &'a AtomicU8,
-        umc_status: &'a AtomicU8,
+        (i2s_status, i2s_peaks): (&'a AtomicU8, &'a [AtomicI32]),
+        (umc_status, umc_peaks): (&'a AtomicU8, &'a [AtomicI32]),
     ) -> Self {
         let path: PathBuf = path.into();
         let data_link = path.join("data.json");
@@ -78,61 +271,68 @@ impl<'a> Reader<'a> {
             read_period: Duration::from_millis(Self::PERIOD_MILLIS),
             i2s_status,
             umc_status,
+            i2s_peaks,
+            umc_peaks,
+            snapshot: Arc::default(),
+            low_space: None,
         }
     }
 
-    fn handle_gps_data_error(&mut self, err: &gps::Error) {
-        self.device_manager.statuses.gps = Status::NoData;
-        warn!("GPS data error: {err}");
+    /// Raised together with pausing the data files while free space is below
+    /// `Settings::min_free_gb`, so the audio writers can pause as well.
+    pub fn set_low_space(&mut self, low_space: &'a AtomicBool) {
+        self.low_space = Some(low_space);
     }
 
-    fn handle_gps_init_error(&mut self, err: &gps::Error) {
-        match err {
-            gps::Error::Uart(uart_err) => {
-                warn!("GPS init failed: {uart_err}");
-                self.device_manager.statuses.gps = Status::Disconnected;
-            }
-            _ => unreachable!(),
-        }
-    }
-
-    fn handle_aht_data_error(&mut self, err: &aht::Error) {
-        self.device_manager.statuses.aht = Status::NoData;
-        error!("AHT10 data error: {err}");
+    /// Shared copy of the latest cycle, updated after its data file is written, for readers
+    /// that shouldn't depend on the data files or the loop timing.
+    pub fn snapshot(&self) -> Arc<Mutex<Snapshot>> {
+        self.snapshot.clone()
     }
 
-    fn handle_aht_init_error(&mut self, err: &aht::Error) {
-        match err {
-            aht::Error::I2c(i2c_err) => {
-                warn!("AHT10 init failed: {i2c_err}");
-                self.device_manager.statuses.aht = Status::Disconnected;
+    /// Writes a cycle to `{nanos}.json` and points the data symlink at it, returning whether
+    /// the data was fully written.
+    fn write_data_file(&self, json_data: &Snapshot, nanos: i64) -> bool {
+        let path = self.path.join(format!("{nanos}.json"));
+        let mut written = false;
+        match File::create(&path) {
+            Ok(file) => {
+                let mut writer = BufWriter::new(file);
+                match serde_json::to_writer(&mut writer, json_data) {
+                    Ok(()) => {
+                        match writer.write_all(b"\n").and_then(|()| writer.flush()) {
+                            Ok(()) => {
+                                written = true;
+                            }
+                            Err(err) => {
+                                error!("Failed to write new line to data file: {err}");
+                            }
+                        }
+                        if self.data_link.exists() {
+                            match std::fs::remove_file(&self.data_link) {
+                                Ok(()) => {}
+                                Err(err) => {
+                                    error!("Failed to remove previous data symlink: {err}");
+                                }
+                            }
+                        }
+                        match std::os::unix::fs::symlink(&path, &self.data_link) {
+                            Ok(()) => {}
+                            Err(err) => {
+                                error!("Failed to create data symlink: {err}");
+                            }
+                        };
+                    }
+                    Err(e) => {
+                        warn!("Failed to serialize data to json: {e}");
+                    }
+                };
             }
-            aht::Error::Aht(_) => unreachable!(),
-        }
-    }
-
-    fn handle_bmp_data_error(&mut self, err: &bmp::Error) {
-        self.device_manager.statuses.bmp = Status::NoData;
-        error!("BMP280 data error: {err}");
-    }
-
-    fn handle_bmp_init_error(&mut self, err: &bmp::Error) {
-        match err {
-            bmp::Error::Bmp(err) => {
-                warn!("BMP280 init failed: {err}");
-                self.device_manager.statuses.bmp = Status::Disconnected;
+            Err(e) => {
+                warn!("Failed to create data file: {e}");
             }
-        }
-    }
-
-    fn handle_ina_data_error(&mut self, err: &ina::Error) {
-        self.device_manager.statuses.ina = Status::NoData;
-        error!("INA219 data error: {err}");
-    }
-
-    fn handle_ina_init_error(&mut self, err: &ina::Error) {
-        self.device_manager.statuses.ina = Status::Disconnected;
-        warn!("INA219 init failed: {err}");
+        };
+        written
     }
 
     #[allow(clippy::too_many_lines)]
@@ -147,44 +347,32 @@ impl<'a> Reader<'a> {
             .name("imu".to_owned())
             .spawn_scoped(s, {
                 let data = imu_data.clone();
-                let bus = self.device_manager.settings.imu_bus;
-                let period = Duration::from_millis(100);
-                let path = self.calib_path.clone();
+                let dm_settings = &self.device_manager.settings;
+                let period = dm_settings.imu_period.max(Duration::from_millis(1));
+                #[allow(clippy::cast_possible_truncation)]
+                let samples = (dm_settings.imu_window.as_millis() / period.as_millis()) as usize;
+                let settings = ImuSettings {
+                    bus: dm_settings.imu_bus,
+                    period,
+                    samples: samples.max(1),
+                    recal_rotation: dm_settings.imu_recal_rotation,
+                    calib_path: self.calib_path.clone(),
+                };
+                let reinit_after = dm_settings.reinit_after;
                 move || {
-                    let samples: usize = 10000 / period.as_millis() as usize;
-                    let mut imu: Option<Imu> = None;
+                    let mut imu = DeviceSlot::<Imu>::default();
+                    imu.set_reinit_after(reinit_after);
+                    let mut status = Status::default();
                     while running.load(Ordering::Relaxed) {
                         let start = Instant::now();
 
-                        if let Some(imu) = imu.as_mut() {
-                            match imu.get_data() {
-                                Ok(d) => {
-                                    *data.lock() = (d, Status::Ok);
-                                }
-                                Err(err) => {
-                                    warn!("{err}");
-                                    data.lock().1 = Status::NoData;
-                                }
-                            }
-                        } else {
-                            match Imu::new(bus, samples, &path) {
-                                Ok(mut device) => match device.calibrate(true) {
-                                    Ok(()) => {
-                                        info! {"IMU device initialized"};
-                                        imu = Some(device);
-                                        data.lock().1 = Status::NoData;
-                                    }
-                                    Err(err) => {
-                                        warn!("{err}");
-                                        data.lock().1 = Status::Disconnected;
-                                    }
-                                },
-                                Err(err) => {
-                                    warn!("IMU init: {err}");
-                                    data.lock().1 = Status::Disconnected;
-                                }
-                            };
+                        let d = imu.poll(&settings, &mut status, "IMU");
+                        let mut guard = data.lock();
+                        if let Some(d) = d {
+                            guard.0 = d;
                         }
+                        guard.1 = status;
+                        drop(guard);
 
                         thread::sleep(period.saturating_sub(start.elapsed()));
                     }
@@ -197,36 +385,23 @@ impl<'a> Reader<'a> {
             .name("wind".to_owned())
             .spawn_scoped(s, {
                 let data = wind_data.clone();
-                let settings = self.device_manager.settings.wind;
+                let settings = self.device_manager.settings.wind.clone();
                 let period = Duration::from_millis(1000);
+                let reinit_after = self.device_manager.settings.reinit_after;
                 move || {
-                    let mut wind: Option<Wind> = None;
+                    let mut wind = DeviceSlot::<Wind>::default();
+                    wind.set_reinit_after(reinit_after);
+                    let mut status = Status::default();
                     while running.load(Ordering::Relaxed) {
                         let start = Instant::now();
 
-                        if let Some(wind) = wind.as_mut() {
-                            match wind.get_data() {
-                                Ok(d) => {
-                                    *data.lock() = (d, Status::Ok);
-                                }
-                                Err(err) => {
-                                    warn!("{err}");
-                                    data.lock().1 = Status::NoData;
-                                }
-                            }
-                        } else {
-                            match Wind::new(settings.port, settings.baud_rate, settings.timeout) {
-                                Ok(device) => {
-                                    info! {"Wind device initialized"};
-                                    wind = Some(device);
-                                    data.lock().1 = Status::NoData;
-                                }
-                                Err(err) => {
-                                    warn!("{err}");
-                                    data.lock().1 = Status::Disconnected;
-                                }
-                            };
+                        let d = wind.poll(&settings, &mut status, "Wind");
+                        let mut guard = data.lock();
+                        if let Some(d) = d {
+                            guard.0 = d;
                         }
+                        guard.1 = status;
+                        drop(guard);
 
                         thread::sleep(period.saturating_sub(start.elapsed()));
                     }
@@ -245,11 +420,58 @@ impl<'a> Reader<'a> {
             .find(|d| d.mount_point() == Path::new("/"));
         //info!("option disk: {disk:?}");
 
-        let (client, (ip, mac)) = if let Some((ip, mac)) = ip {
-            (Some(reqwest::blocking::Client::new()), (ip, mac))
+        let reinit_after = self.device_manager.settings.reinit_after;
+        self.device_manager.ina.set_reinit_after(reinit_after);
+
+        let mut components = Components::new_with_refreshed_list();
+        let mut temps = CircularVector::new(self.device_manager.settings.temp_window.max(1), 0.0);
+        let mut temps_primed = false;
+
+        let mut storage_failures = 0u32;
+        let mut audio_silent_since: Option<Instant> = None;
+        let mut cycles_since_prune = 0u32;
+        let mut ndjson = self
+            .device_manager
+            .settings
+            .ndjson
+            .map(|settings| NdjsonLog::new(&self.path, settings));
+        let mut csv_export = self
+            .device_manager
+            .settings
+            .csv_export
+            .then(|| CsvExport::new(&self.path));
+
+        let ip = ip.filter(|_| self.device_manager.settings.publish);
+        let (publisher, (ip, mac)) = if let Some((ip, mac)) = ip {
+            let settings = &self.device_manager.settings;
+            let client = reqwest::blocking::Client::builder()
+                .connect_timeout(settings.publish_connect_timeout)
+                .timeout(settings.publish_timeout)
+                .build()
+                .unwrap_or_else(|err| {
+                    warn!("Failed to build HTTP client with timeouts: {err}");
+                    reqwest::blocking::Client::new()
+                });
+            let spool = settings.publish_spool_bytes.and_then(|max_bytes| {
+                Spool::open(&self.path.join("outbox"), max_bytes)
+                    .map_err(|err| warn!("Failed to open publish spool: {err}"))
+                    .ok()
+            });
+            let url = settings.publish_url.clone();
+            let publisher = Publisher::spawn(s, running, client, url, settings.publish_queue, spool);
+            (Some(publisher), (ip, mac))
         } else {
             (None, (String::new(), String::new()))
         };
+        if let Some(port) = self.device_manager.settings.status_port {
+            status_server::spawn(s, running, port, self.snapshot());
+        }
+        let mqtt = self
+            .device_manager
+            .settings
+            .mqtt
+            .clone()
+            .map(|settings| MqttPublisher::spawn(s, running, settings));
         //let client = reqwest::blocking::Client::new();
         while running.load(Ordering::Relaxed) {
             let start = Instant::now();
@@ -278,89 +500,31 @@ impl<'a> Reader<'a> {
                 self.device_manager.statuses.wind = Status::NoData;
             }
 
-            if let Some(gps) = self.device_manager.gps.as_mut() {
-                match gps.get_data() {
-                    Ok(d) => {
-                        self.device_manager.statuses.gps = Status::Ok;
-                        data.gps = Some(d);
-                    }
-                    Err(e) => {
-                        self.handle_gps_data_error(&e);
-                    }
-                }
-            } else {
-                match self.device_manager.try_set_gps() {
-                    Ok(()) => {
-                        info!("GPS device initialized");
-                    }
-                    Err(e) => {
-                        self.handle_gps_init_error(&e);
-                    }
-                }
-            }
-
-            if let Some(aht) = self.device_manager.aht.as_mut() {
-                match aht.get_data() {
-                    Ok(d) => {
-                        self.device_manager.statuses.aht = Status::Ok;
-                        data.aht = Some(d);
-                    }
-                    Err(e) => {
-                        self.handle_aht_data_error(&e);
-                    }
-                }
-            } else {
-                match self.device_manager.try_set_aht() {
-                    Ok(()) => {
-                        info!("AHT10 device initialized");
-                    }
-                    Err(e) => {
-                        self.handle_aht_init_error(&e);
-                    }
+            let dm = &mut self.device_manager;
+            data.gps = dm.gps.poll(&dm.settings.gps, &mut dm.statuses.gps, "GPS");
+            if let Some(fix) = &data.gps {
+                if let Err(err) = dm.gps_filter.check(&dm.settings.gps.outlier, fix) {
+                    warn!("GPS {err}");
+                    dm.statuses.gps = Status::NoData;
+                    data.gps = None;
                 }
             }
-
-            if let Some(bmp) = self.device_manager.bmp.as_mut() {
-                match bmp.get_data() {
-                    Ok(d) => {
-                        self.device_manager.statuses.bmp = Status::Ok;
-                        data.bmp = Some(d);
-                    }
-                    Err(e) => {
-                        self.handle_bmp_data_error(&e);
-                    }
-                }
-            } else {
-                match self.device_manager.try_set_bmp() {
-                    Ok(()) => {
-                        info!("BMP280 device initialized");
-                    }
-                    Err(e) => {
-                        self.handle_bmp_init_error(&e);
-                    }
-                }
+            if data.gps.is_none() {
+                data.gps = dm.settings.fixed_location.map(gps::Data::fixed);
             }
-
-            if let Some(ina) = self.device_manager.ina.as_mut() {
-                match ina.get_data() {
-                    Ok(d) => {
-                        self.device_manager.statuses.ina = Status::Ok;
-                        data.ina = Some(d);
-                    }
-                    Err(e) => {
-                        self.handle_ina_data_error(&e);
-                    }
-                }
-            } else {
-                match self.device_manager.try_set_ina() {
-                    Ok(()) => {
-                        info!("INA219 device initialized");
-                    }
-                    Err(e) => {
-                        self.handle_ina_init_error(&e);
-                    }
+            data.aht = dm.aht.poll(&dm.settings.aht_bus, &mut dm.statuses.aht, "AHT10");
+            data.bmp = dm.bmp.poll(&dm.settings.bmp, &mut dm.statuses.bmp, "BMP280");
+            if dm.settings.bmp_gps_reference {
+                let fix = data.gps.as_ref().and_then(|gps| gps.altitude);
+                if let (Some(altitude), Some(bmp)) = (fix, &data.bmp) {
+                    let target = Bmp::ground_pressure(bmp.pressure * 100.0, altitude);
+                    let current = dm.settings.bmp.ground_pressure;
+                    let weight = Self::GROUND_PRESSURE_WEIGHT;
+                    dm.set_ground_pressure(current + weight * (target - current));
                 }
             }
+            data.ina = dm.ina.poll(&dm.settings.ina_calibration, &mut dm.statuses.ina, "INA219");
+            dm.statuses.update_last_ok(chrono::Utc::now());
 
             //self.device_manager.statuses.i2s = self.i2s_status.load(Ordering::Relaxed).into();
             //self.device_manager.statuses.umc = self.umc_status.load(Ordering::Relaxed).into();
@@ -368,6 +532,29 @@ impl<'a> Reader<'a> {
                 self.i2s_status.fetch_and(0, Ordering::Relaxed).into();
             self.device_manager.statuses.umc =
                 self.umc_status.fetch_and(0, Ordering::Relaxed).into();
+            self.device_manager.statuses.max_i2s_ch =
+                self.i2s_peaks.iter().map(|peak| peak.swap(0, Ordering::Relaxed)).collect();
+            self.device_manager.statuses.max_umc_ch =
+                self.umc_peaks.iter().map(|peak| peak.swap(0, Ordering::Relaxed)).collect();
+
+            let statuses = &mut self.device_manager.statuses;
+            if statuses.i2s == Status::NoData && statuses.umc == Status::NoData {
+                let since = *audio_silent_since.get_or_insert(start);
+                if since.elapsed() >= self.device_manager.settings.audio_fault_after {
+                    if statuses.audio_fault != Status::OtherError {
+                        error!("Both microphones silent for {} s", since.elapsed().as_secs());
+                    }
+                    statuses.audio_fault = Status::OtherError;
+                } else {
+                    statuses.audio_fault = Status::Ok;
+                }
+            } else {
+                if statuses.audio_fault == Status::OtherError {
+                    info!("Microphones no longer both silent");
+                }
+                audio_silent_since = None;
+                statuses.audio_fault = Status::Ok;
+            }
 
             if let Some(disk) = disk.as_mut() {
                 disk.refresh_specifics(DiskRefreshKind::nothing().with_storage());
@@ -376,68 +563,100 @@ impl<'a> Reader<'a> {
                 self.device_manager.statuses.free = free;
             }
 
-            #[allow(clippy::items_after_statements)]
-            #[derive(Serialize, Deserialize)]
-            struct JsonData {
-                statuses: Statuses,
-                data: Data,
+            components.refresh(false);
+            let temp_peak = components
+                .list()
+                .iter()
+                .filter_map(Component::temperature)
+                .fold(f32::NAN, f32::max);
+            if !temp_peak.is_nan() {
+                if temps_primed {
+                    temps.push(temp_peak);
+                } else {
+                    temps.reset(temp_peak);
+                    temps_primed = true;
+                }
+                self.device_manager.statuses.temp = temps.mean();
+                self.device_manager.statuses.temp_peak = temp_peak;
             }
-            let json_data = JsonData {
-                statuses: self.device_manager.statuses,
+
+            let mut json_data = Snapshot {
+                statuses: self.device_manager.statuses.clone(),
                 data,
             };
 
-            let nanos = chrono::Utc::now().timestamp_nanos_opt().unwrap();
-            let path = self.path.join(format!("{nanos}.json"));
-            match File::create(&path) {
-                Ok(file) => {
-                    let mut writer = BufWriter::new(file);
-                    match serde_json::to_writer(&mut writer, &json_data) {
-                        Ok(()) => {
-                            match writer.write(b"\n") {
-                                Ok(_) => {}
-                                Err(err) => {
-                                    error!("Failed to write new line to data file: {err}");
-                                }
-                            }
-                            if self.data_link.exists() {
-                                match std::fs::remove_file(&self.data_link) {
-                                    Ok(()) => {}
-                                    Err(err) => {
-                                        error!("Failed to remove previous data symlink: {err}");
-                                    }
-                                }
-                            }
-                            match std::os::unix::fs::symlink(&path, &self.data_link) {
-                                Ok(()) => {}
-                                Err(err) => {
-                                    error!("Failed to create data symlink: {err}");
-                                }
-                            };
-                        }
-                        Err(e) => {
-                            warn!("Failed to serialize data to json: {e}");
-                        }
-                    };
+            let now = chrono::Utc::now();
+            let nanos = now.timestamp_nanos_opt().unwrap();
+            let low_space = self.device_manager.statuses.free
+                < self.device_manager.settings.min_free_gb
+                && disk.is_some();
+            if let Some(flag) = self.low_space {
+                if flag.swap(low_space, Ordering::Relaxed) != low_space {
+                    if low_space {
+                        warn!(
+                            "Free space below {} GiB, pausing file writes",
+                            self.device_manager.settings.min_free_gb
+                        );
+                    } else {
+                        info!("Free space recovered, resuming file writes");
+                    }
+                }
+            }
+
+            let mut written = !low_space
+                && match ndjson.as_mut() {
+                    Some(ndjson) => ndjson.append(&json_data, nanos),
+                    None => self.write_data_file(&json_data, nanos),
+                };
+
+            if let Some(keep) = self.device_manager.settings.data_files_kept {
+                cycles_since_prune += 1;
+                if cycles_since_prune >= Self::PRUNE_CYCLES {
+                    cycles_since_prune = 0;
+                    if let Err(err) = prune_data_files(&self.path, keep) {
+                        warn!("Failed to remove old data files: {err}");
+                    }
                 }
-                Err(e) => {
-                    warn!("Failed to create data file: {e}");
+            }
+
+            if let Some(csv_export) = csv_export.as_mut().filter(|_| !low_space) {
+                if let Err(err) = csv_export.append(now, &json_data.statuses, &json_data.data) {
+                    written = false;
+                    error!("Failed to append to sensors csv: {err}");
                 }
+            }
+
+            if written {
+                storage_failures = 0;
+            } else if !low_space {
+                storage_failures += 1;
+            }
+            let storage = if low_space {
+                Status::NoData
+            } else if storage_failures >= self.device_manager.settings.storage_failures {
+                Status::OtherError
+            } else {
+                Status::Ok
             };
+            match (self.device_manager.statuses.storage, storage) {
+                (Status::OtherError, Status::Ok) => info!("Data file writes recovered"),
+                (Status::Ok | Status::Disconnected, Status::OtherError) => {
+                    error!("{storage_failures} consecutive data file writes failed");
+                }
+                _ => {}
+            }
+            self.device_manager.statuses.storage = storage;
+            json_data.statuses.storage = storage;
+            *self.snapshot.lock() = json_data.clone();
 
-            if let Some(client) = client.as_ref() {
+            if publisher.is_some() || mqtt.is_some() {
                 match serde_json::to_string(&json_data) {
                     Ok(str) => {
-                        let msg = format!("{ip} {mac} {str}");
-                        match client
-                            .post("http://mlynarczyk.edu.pl:8080/andros/publish")
-                            .body(msg)
-                            .send()
-                        {
-                            Ok(_) => {}
-                            Err(err) => {
-                                warn!("Failed to make POST request: {err}");
-                            }
+                        if let Some(mqtt) = mqtt.as_ref() {
+                            mqtt.publish(str.clone());
+                        }
+                        if let Some(publisher) = publisher.as_ref() {
+                            publisher.publish(format!("{ip} {mac} {str}"));
                         }
                     }
                     Err(e) => {
@@ -453,3 +672,147 @@ impl<'a> Reader<'a> {
         //wind_thread.join().unwrap();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Dotted paths of every key in `value`, arrays are not descended into
+    fn keys(value: &serde_json::Value, prefix: &str, out: &mut Vec<String>) {
+        if let serde_json::Value::Object(map) = value {
+            for (key, value) in map {
+                let path = if prefix.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{prefix}.{key}")
+                };
+                keys(value, &path, out);
+                out.push(path);
+            }
+        }
+    }
+
+    // Some sensor data fields are private to their modules
+    #[allow(clippy::field_reassign_with_default)]
+    #[test]
+    fn snapshot_wire_format() {
+        let mut statuses = Statuses::default();
+        statuses.gps = Status::Ok;
+        statuses.update_last_ok(chrono::Utc::now());
+        let mut imu = imu::Data::default();
+        imu.heading = Some(90.0);
+        let snapshot = Snapshot {
+            statuses,
+            data: Data {
+                gps: Some(gps::Data::fixed((52.2, 21.0, 100.0))),
+                aht: Some(aht::Data::default()),
+                wind: Some(wind::Data::default()),
+                imu: Some(imu),
+                bmp: Some(bmp::Data::default()),
+                ina: Some(ina::Data::default()),
+            },
+        };
+        let value = serde_json::to_value(&snapshot).unwrap();
+        let mut actual = Vec::new();
+        keys(&value, "", &mut actual);
+        actual.sort_unstable();
+        let expected = [
+            "data",
+            "data.aht", "data.aht.humidity", "data.aht.temperature", "data.aht.timestamp",
+            "data.bmp", "data.bmp.altitude", "data.bmp.pressure", "data.bmp.temperature",
+            "data.bmp.timestamp",
+            "data.gps", "data.gps.altitude", "data.gps.configured", "data.gps.course",
+            "data.gps.fix_quality", "data.gps.latitude", "data.gps.longitude",
+            "data.gps.satellites", "data.gps.speed_knots", "data.gps.timestamp",
+            "data.imu", "data.imu.acc", "data.imu.angle", "data.imu.gyro", "data.imu.heading",
+            "data.imu.mag", "data.imu.pitch", "data.imu.quat", "data.imu.roll", "data.imu.rotation",
+            "data.imu.temp", "data.imu.timestamp", "data.imu.yaw",
+            "data.ina", "data.ina.bus_voltage", "data.ina.calibrated", "data.ina.current",
+            "data.ina.power", "data.ina.shunt_voltage", "data.ina.timestamp",
+            "data.wind", "data.wind.dir", "data.wind.speed", "data.wind.timestamp",
+            "statuses", "statuses.aht", "statuses.audio_fault", "statuses.bmp", "statuses.free",
+            "statuses.gps", "statuses.i2s", "statuses.imu", "statuses.ina",
+            "statuses.last_ok", "statuses.last_ok.aht", "statuses.last_ok.bmp",
+            "statuses.last_ok.gps", "statuses.last_ok.imu", "statuses.last_ok.ina",
+            "statuses.last_ok.wind",
+            "statuses.max_i2s_ch", "statuses.max_umc_ch", "statuses.storage", "statuses.temp",
+            "statuses.temp_peak", "statuses.umc", "statuses.wind",
+        ];
+        assert_eq!(actual, expected);
+
+        let data = &value["data"];
+        assert!(data["ina"]["current"].is_f64());
+        assert!(data["gps"]["altitude"].is_f64());
+        assert_eq!(data["gps"]["configured"], true);
+        assert!(data["imu"]["heading"].is_f64());
+        assert_eq!(data["imu"]["rotation"].as_array().map(Vec::len), Some(3));
+        assert_eq!(value["statuses"]["gps"], "Ok");
+        assert!(value["statuses"]["last_ok"]["gps"].is_string());
+        assert!(value["statuses"]["last_ok"]["aht"].is_null());
+
+        let round_trip: Snapshot = serde_json::from_value(value.clone()).unwrap();
+        assert_eq!(serde_json::to_value(&round_trip).unwrap(), value);
+    }
+
+    #[test]
+    fn missing_sensors_are_null() {
+        let value = serde_json::to_value(Snapshot::default()).unwrap();
+        for sensor in ["gps", "aht", "wind", "imu", "bmp", "ina"] {
+            assert!(value["data"][sensor].is_null(), "{sensor}");
+        }
+    }
+
+    #[test]
+    fn prune_covers_ndjson() {
+        let dir = tempfile::tempdir().unwrap();
+        let names = ["100.json", "200.ndjson", "300.json", "400.ndjson", "current.ndjson"];
+        for name in names {
+            File::create(dir.path().join(name)).unwrap();
+        }
+        prune_data_files(dir.path(), 2).unwrap();
+        let mut left: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        left.sort();
+        assert_eq!(left, ["300.json", "400.ndjson", "current.ndjson"]);
+
+        prune_data_files(dir.path(), 0).unwrap();
+        assert!(dir.path().join("400.ndjson").exists());
+    }
+
+    /// Always fails with the error given in its settings
+    struct Failing(&'static str);
+
+    impl Device for Failing {
+        type Data = ();
+        type Error = &'static str;
+        type Settings = &'static str;
+
+        fn init(settings: &Self::Settings) -> Result<Self, Self::Error> {
+            Ok(Self(settings))
+        }
+
+        fn get_data(&mut self) -> Result<Self::Data, Self::Error> {
+            Err(self.0)
+        }
+
+        fn not_ready(err: &Self::Error) -> bool {
+            *err == "not ready"
+        }
+    }
+
+    #[test]
+    fn not_ready_is_not_a_failure() {
+        let mut status = Status::default();
+        for (error, reinitialized) in [("not ready", false), ("bus error", true)] {
+            let mut slot = DeviceSlot::<Failing>::default();
+            slot.set_reinit_after(2);
+            for _ in 0..3 {
+                slot.poll(&error, &mut status, "test");
+            }
+            assert_eq!(slot.device_mut().is_none(), reinitialized, "{error}");
+            assert_eq!(status, Status::NoData);
+        }
+    }
+}
@@ -1,12 +1,19 @@
 use core::fmt;
 use std::fmt::Debug;
+use std::thread;
+use std::time::Duration;
 
 use aht10::AHT10;
+use chrono::{DateTime, Utc};
+use log::debug;
 use rppal::hal::Delay;
+#[cfg(not(feature = "mock-hardware"))]
 use rppal::i2c::I2c;
 use serde::{Deserialize, Serialize};
 
-use super::Device;
+use super::{out_of_range, Device};
+#[cfg(feature = "mock-hardware")]
+use crate::mock::I2c;
 
 pub struct Aht {
     device: AHT10<I2c, Delay>,
@@ -35,35 +42,54 @@ impl From<aht10::Error<rppal::i2c::Error>> for Error {
 }
 
 impl Aht {
+    /// Attempts at initialization, the sensor NAKs for about 40 ms after power-up
+    const INIT_ATTEMPTS: u32 = 4;
+    const INIT_RETRY_DELAY: Duration = Duration::from_millis(20);
+
     pub fn new(bus: u8) -> Result<Self, Error> {
-        let i2c = I2c::with_bus(bus)?;
-        let delay = Delay::new();
-        let aht = AHT10::new(i2c, delay)?;
-        Ok(Self { device: aht })
+        let mut attempt = 1;
+        loop {
+            let i2c = I2c::with_bus(bus)?;
+            match AHT10::new(i2c, Delay::new()) {
+                Ok(aht) => return Ok(Self { device: aht }),
+                Err(_) if attempt < Self::INIT_ATTEMPTS => {
+                    debug!("AHT10 not ready on attempt {attempt}, retrying");
+                    attempt += 1;
+                    thread::sleep(Self::INIT_RETRY_DELAY);
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct Data {
-    humidity: f32,
-    temperature: f32,
+    pub humidity: f32,
+    pub temperature: f32,
+    timestamp: DateTime<Utc>,
 }
 
 impl Device for Aht {
     type Data = Data;
     type Error = Error;
-    //type Settings = u8;
+    type Settings = u8;
+
+    fn init(settings: &Self::Settings) -> Result<Self, Self::Error> {
+        Self::new(*settings)
+    }
+
+    fn implausible(data: &Self::Data) -> Option<String> {
+        out_of_range("humidity", data.humidity, 0.0..=100.0)
+            .or_else(|| out_of_range("temperature", data.temperature, -40.0..=85.0))
+    }
 
     fn get_data(&mut self) -> Result<Self::Data, Self::Error> {
         let data = self.device.read()?;
         Ok(Self::Data {
             humidity: data.0.rh(),
             temperature: data.1.celsius(),
+            timestamp: Utc::now(),
         })
     }
-
-    //fn init(&mut self, settings: Self::Settings) -> Result<(), Self::Error> {
-    //    self.device = Self::new(settings)?;
-    //    Ok(())
-    //}
 }
@@ -1,12 +1,20 @@
 use core::fmt;
 use std::fmt::Debug;
 
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
-use super::Device;
+use super::circular_buffer::CircularVector;
+use super::device_manager::BmpSettings;
+use super::{out_of_range, Device};
+#[cfg(feature = "mock-hardware")]
+use crate::mock::bmp280;
 
 pub struct Bmp {
     device: bmp280::Bmp280,
+    pressures: CircularVector<f32>,
+    primed: bool,
+    ground_pressure: f32,
 }
 
 impl Debug for Bmp {
@@ -18,19 +26,45 @@ impl Debug for Bmp {
 }
 
 impl Bmp {
-    pub fn new() -> Result<Self, Error> {
+    pub const STANDARD_PRESSURE: f32 = 101_325.0;
+
+    /// `window` is the number of recent pressure samples the reported altitude is
+    /// median-filtered over, `ground_pressure` is the altitude reference in Pa.
+    pub fn new(window: usize, ground_pressure: f32) -> Result<Self, Error> {
         let bmp = bmp280::Bmp280Builder::new()
-            .ground_pressure(101_325.0)
+            .ground_pressure(ground_pressure)
             .build()?;
-        Ok(Self { device: bmp })
+        Ok(Self {
+            device: bmp,
+            pressures: CircularVector::new(window.max(1), 0.0),
+            primed: false,
+            ground_pressure,
+        })
+    }
+
+    /// Updates the reference pressure in Pa that the reported altitude is relative to.
+    pub fn set_ground_pressure(&mut self, pa: f32) {
+        self.ground_pressure = pa;
+    }
+
+    /// Barometric altitude in meters for `pressure` relative to `ground_pressure` (both in Pa).
+    fn altitude(pressure: f32, ground_pressure: f32) -> f32 {
+        44_330.0 * (1.0 - (pressure / ground_pressure).powf(1.0 / 5.255))
+    }
+
+    /// Reference pressure in Pa that puts `pressure` in Pa at `altitude` meters, the inverse of
+    /// `altitude`.
+    pub fn ground_pressure(pressure: f32, altitude: f32) -> f32 {
+        pressure / (1.0 - altitude / 44_330.0).powf(5.255)
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct Data {
-    temperature: f32,
-    pressure: f32,
+    pub temperature: f32,
+    pub pressure: f32,
     altitude: f32,
+    timestamp: DateTime<Utc>,
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -42,11 +76,45 @@ pub enum Error {
 impl Device for Bmp {
     type Data = Data;
     type Error = Error;
+    type Settings = BmpSettings;
+
+    fn init(settings: &Self::Settings) -> Result<Self, Self::Error> {
+        Self::new(settings.window, settings.ground_pressure)
+    }
+
+    fn implausible(data: &Self::Data) -> Option<String> {
+        out_of_range("pressure", data.pressure, 300.0..=1100.0)
+            .or_else(|| out_of_range("temperature", data.temperature, -40.0..=85.0))
+    }
 
     fn get_data(&mut self) -> Result<Self::Data, Self::Error> {
+        let timestamp = Utc::now();
         let temperature = self.device.temperature_celsius()?;
         let pressure = self.device.pressure_kpa()? * 10.0;
-        let altitude = self.device.altitude_m_relative(101_325.0)?;
-        Ok(Self::Data { temperature, pressure, altitude })
+
+        if self.primed {
+            self.pressures.push(pressure);
+        } else {
+            self.pressures.reset(pressure);
+            self.primed = true;
+        }
+        // hPa to Pa
+        let altitude = Self::altitude(self.pressures.median() * 100.0, self.ground_pressure);
+
+        Ok(Self::Data { temperature, pressure, altitude, timestamp })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ground_pressure_inverts_altitude() {
+        for altitude in [-50.0, 0.0, 120.0, 1500.0] {
+            let ground = Bmp::ground_pressure(95_000.0, altitude);
+            assert!((Bmp::altitude(95_000.0, ground) - altitude).abs() < 0.5, "{altitude}");
+        }
+        assert!((Bmp::ground_pressure(Bmp::STANDARD_PRESSURE, 0.0) - 101_325.0).abs() < 0.1);
     }
 }
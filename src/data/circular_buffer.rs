@@ -0,0 +1,74 @@
+pub trait Buffer {
+    type Container;
+}
+
+impl<T> Buffer for Vec<T> {
+    type Container = Vec<T>;
+}
+
+pub struct CircularBuffer<B: Buffer> {
+    pub(super) size: usize,
+    pub(super) buf: B::Container,
+    pub(super) index: usize,
+}
+
+impl<B: Buffer> CircularBuffer<B> {
+    fn increment_index(&mut self) {
+        self.index += 1;
+        self.index %= self.size;
+    }
+}
+
+impl<T: Clone + Copy> CircularBuffer<Vec<T>> {
+    pub fn new(size: usize, fill: T) -> Self {
+        Self {
+            size,
+            buf: vec![fill; size],
+            index: 0,
+        }
+    }
+
+    pub fn push(&mut self, value: T) {
+        self.buf[self.index] = value;
+        self.increment_index();
+    }
+
+    pub fn newest(&self) -> T {
+        if self.index == 0 {
+            return self.buf[self.size - 1];
+        }
+        self.buf[self.index - 1]
+    }
+
+    pub fn oldest(&self) -> T {
+        self.buf[self.index]
+    }
+
+    pub fn reset(&mut self, fill: T) {
+        self.index = 0;
+        self.buf = vec![fill; self.size];
+    }
+
+    //fn iter(&self) -> impl Iterator<Item = &T> {
+    //    //self.buf.iter().skip(self.index).chain(self.buf.iter().take(self.index))
+    //    self.buf.iter().cycle().skip(self.index).take(self.size)
+    //}
+}
+
+impl CircularBuffer<Vec<f32>> {
+    /// Mean of all values currently held in the buffer.
+    pub fn mean(&self) -> f32 {
+        #[allow(clippy::cast_precision_loss)]
+        let len = self.size as f32;
+        self.buf.iter().sum::<f32>() / len
+    }
+
+    /// Median of all values currently held in the buffer.
+    pub fn median(&self) -> f32 {
+        let mut sorted = self.buf.clone();
+        sorted.sort_by(f32::total_cmp);
+        sorted[self.size / 2]
+    }
+}
+
+pub type CircularVector<T> = CircularBuffer<Vec<T>>;
@@ -0,0 +1,111 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use log::info;
+
+use super::device_manager::Statuses;
+use super::Data;
+
+/// Formats an optional value, leaving the cell empty when missing
+fn cell<T: ToString>(value: Option<T>) -> String {
+    value.map(|v| v.to_string()).unwrap_or_default()
+}
+
+/// Appends one row per read cycle to a flat `sensors.csv`, for tooling that doesn't handle the
+/// nested JSON files.
+///
+/// The first column holds the column set version. A file written with a different header is
+/// moved aside when first opened, so every row in `sensors.csv` matches its header.
+pub struct CsvExport {
+    path: PathBuf,
+    checked: bool,
+}
+
+impl CsvExport {
+    /// Bump whenever `HEADER` changes
+    const VERSION: u32 = 1;
+    const HEADER: &'static str = "version,timestamp,aht_temperature,humidity,bmp_temperature,\
+        pressure,latitude,longitude,wind_dir,wind_speed,bus_voltage,temp,gps,aht,wind,imu,bmp,\
+        ina,i2s,umc,storage";
+
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            path: dir.join("sensors.csv"),
+            checked: false,
+        }
+    }
+
+    /// Moves an existing file aside if its header doesn't match the current column set.
+    fn check_header(&self) -> std::io::Result<()> {
+        let Ok(file) = File::open(&self.path) else {
+            return Ok(());
+        };
+        let mut header = String::new();
+        BufReader::new(file).read_line(&mut header)?;
+        if header.trim_end() != Self::HEADER {
+            let nanos = Utc::now().timestamp_nanos_opt().unwrap();
+            let old = self.path.with_extension(format!("csv.{nanos}"));
+            std::fs::rename(&self.path, &old)?;
+            info!("Moved sensors.csv with an old column set to {}", old.display());
+        }
+        Ok(())
+    }
+
+    pub fn append(
+        &mut self,
+        timestamp: DateTime<Utc>,
+        statuses: &Statuses,
+        data: &Data,
+    ) -> std::io::Result<()> {
+        if !self.checked {
+            self.check_header()?;
+            self.checked = true;
+        }
+
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        if file.metadata()?.len() == 0 {
+            writeln!(file, "{}", Self::HEADER)?;
+        }
+
+        let aht = data.aht.as_ref();
+        let bmp = data.bmp.as_ref();
+        let gps = data.gps.as_ref();
+        let wind = data.wind.as_ref();
+        let ina = data.ina.as_ref();
+        let row = [
+            Self::VERSION.to_string(),
+            timestamp.to_rfc3339(),
+            cell(aht.map(|d| d.temperature)),
+            cell(aht.map(|d| d.humidity)),
+            cell(bmp.map(|d| d.temperature)),
+            cell(bmp.map(|d| d.pressure)),
+            cell(gps.map(|d| d.latitude)),
+            cell(gps.map(|d| d.longitude)),
+            cell(wind.map(|d| d.dir)),
+            cell(wind.map(|d| d.speed)),
+            cell(ina.map(|d| d.bus_voltage)),
+            statuses.temp.to_string(),
+        ]
+        .into_iter()
+        .chain(
+            [
+                statuses.gps,
+                statuses.aht,
+                statuses.wind,
+                statuses.imu,
+                statuses.bmp,
+                statuses.ina,
+                statuses.i2s,
+                statuses.umc,
+                statuses.storage,
+            ]
+            .map(|status| (status as u8).to_string()),
+        )
+        .collect::<Vec<_>>()
+        .join(",");
+        writeln!(file, "{row}")?;
+        file.flush()
+    }
+}
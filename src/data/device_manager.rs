@@ -1,24 +1,24 @@
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
+use super::gps::{OutlierFilter, OutlierGate};
 use super::ina::{self, Ina};
-use super::{aht, bmp, gps};
-use crate::data::{Aht, Bmp, Gps};
+use super::mqtt::MqttSettings;
+use super::ndjson::NdjsonSettings;
+use super::wind::WindScaling;
+use crate::data::{Aht, Bmp, DeviceSlot, Gps};
+use std::path::PathBuf;
 use std::time::Duration;
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
 pub enum Status {
     Ok = 0,
     NoData = 1,
+    #[default]
     Disconnected = 2,
     OtherError = 3,
 }
 
-impl Default for Status {
-    fn default() -> Self {
-        Self::Disconnected
-    }
-}
-
 impl From<u8> for Status {
     fn from(value: u8) -> Self {
         match value {
@@ -38,7 +38,8 @@ impl From<u8> for Status {
     }
 }
 
-#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+/// Serialized with the data of every cycle, statuses as their variant names
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Statuses {
     pub gps: Status,
     pub aht: Status,
@@ -48,17 +49,64 @@ pub struct Statuses {
     pub ina: Status,
     pub i2s: Status,
     pub umc: Status,
+    /// Peak absolute sample of each I2S channel since the previous cycle
+    pub max_i2s_ch: Vec<i32>,
+    /// Peak absolute sample of each UMC channel since the previous cycle
+    pub max_umc_ch: Vec<i32>,
+    /// Both capture paths silent for at least `Settings::audio_fault_after`, usually a power or
+    /// preamp fault
+    pub audio_fault: Status,
+    /// `NoData` while files aren't written because free space is below `Settings::min_free_gb`
+    pub storage: Status,
     pub free: f32,
+    /// Hottest component temperature averaged over the last `Settings::temp_window` cycles
+    pub temp: f32,
+    /// Hottest component temperature in the current cycle
+    pub temp_peak: f32,
+    pub last_ok: LastOk,
+}
+
+impl Statuses {
+    /// Stamps `last_ok` of every sensor reporting `Status::Ok` this cycle with `now`.
+    pub fn update_last_ok(&mut self, now: DateTime<Utc>) {
+        let sensors = [
+            (self.gps, &mut self.last_ok.gps),
+            (self.aht, &mut self.last_ok.aht),
+            (self.wind, &mut self.last_ok.wind),
+            (self.imu, &mut self.last_ok.imu),
+            (self.bmp, &mut self.last_ok.bmp),
+            (self.ina, &mut self.last_ok.ina),
+        ];
+        for (status, last_ok) in sensors {
+            if status == Status::Ok {
+                *last_ok = Some(now);
+            }
+        }
+    }
+}
+
+/// Time of the last successful reading of each sensor, `None` if it never produced one since
+/// startup. Tells a momentary glitch apart from a long-dead sensor.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct LastOk {
+    pub gps: Option<DateTime<Utc>>,
+    pub aht: Option<DateTime<Utc>>,
+    pub wind: Option<DateTime<Utc>>,
+    pub imu: Option<DateTime<Utc>>,
+    pub bmp: Option<DateTime<Utc>>,
+    pub ina: Option<DateTime<Utc>>,
 }
 
 #[derive(Default)]
 pub struct DeviceManager {
-    pub gps: Option<Gps>,
-    pub aht: Option<Aht>,
+    pub gps: DeviceSlot<Gps>,
+    pub aht: DeviceSlot<Aht>,
     //pub wind: Option<Wind>,
     //pub imu: Option<Imu>,
-    pub bmp: Option<Bmp>,
-    pub ina: Option<Ina>,
+    pub bmp: DeviceSlot<Bmp>,
+    pub ina: DeviceSlot<Ina>,
+    /// Kept here rather than in `Gps`, which is dropped when the receiver is reinitialized
+    pub gps_filter: OutlierFilter,
     pub settings: Settings,
     pub statuses: Statuses,
 }
@@ -68,76 +116,185 @@ impl DeviceManager {
         Self::default()
     }
 
-    pub fn try_set_gps(&mut self) -> Result<(), gps::Error> {
-        let UartDeviceSettings { port, baud_rate, timeout } = self.settings.gps;
-        self.gps = Some(Gps::new(port, baud_rate, timeout)?);
-        self.statuses.gps = Status::Ok;
-        Ok(())
+    /// Updates the BMP280 altitude reference in Pa, keeping it across reinitializations.
+    pub fn set_ground_pressure(&mut self, pa: f32) {
+        self.settings.bmp.ground_pressure = pa;
+        if let Some(bmp) = self.bmp.device_mut() {
+            bmp.set_ground_pressure(pa);
+        }
     }
+}
 
-    pub fn try_set_aht(&mut self) -> Result<(), aht::Error> {
-        self.aht = Some(Aht::new(self.settings.aht_bus)?);
-        self.statuses.aht = Status::Ok;
-        Ok(())
-    }
+#[derive(Debug, Clone)]
+pub struct UartDeviceSettings {
+    pub port: String,
+    pub baud_rate: u32,
+    pub timeout: Duration,
+}
 
-    //pub fn try_set_wind(&mut self) -> Result<(), wind::Error> {
-    //    let UartDeviceSettings { port, baud_rate, timeout } = self.settings.wind;
-    //    self.wind = Some(Wind::new(port, baud_rate, timeout)?);
-    //    self.statuses.wind = Status::Ok;
-    //    Ok(())
-    //}
-
-    //pub fn try_set_imu(&mut self) -> Result<(), imu::Error> {
-    //    let mut imu = Imu::new(self.settings.imu_bus)?;
-    //    imu.calibrate()?;
-    //    self.imu = Some(imu);
-    //    self.statuses.imu = Status::Ok;
-    //    Ok(())
-    //}
-
-    pub fn try_set_bmp(&mut self) -> Result<(), bmp::Error> {
-        self.bmp = Some(Bmp::new()?);
-        self.statuses.bmp = Status::Ok;
-        Ok(())
-    }
+#[derive(Debug, Clone)]
+pub struct GpsSettings {
+    pub uart: UartDeviceSettings,
+    pub outlier: OutlierGate,
+}
 
-    pub fn try_set_ina(&mut self) -> Result<(), ina::Error> {
-        self.ina = Some(Ina::new()?);
-        self.statuses.ina = Status::Ok;
-        Ok(())
-    }
+#[derive(Debug, Clone)]
+pub struct WindSettings {
+    pub uart: UartDeviceSettings,
+    pub scaling: WindScaling,
 }
 
 #[derive(Debug, Clone, Copy)]
-pub struct UartDeviceSettings {
-    pub port: &'static str,
-    pub baud_rate: u32,
-    pub timeout: Duration,
+pub struct BmpSettings {
+    /// Number of pressure samples the altitude is median-filtered over
+    pub window: usize,
+    /// Altitude reference pressure in Pa
+    pub ground_pressure: f32,
+}
+
+#[derive(Debug, Clone)]
+pub struct ImuSettings {
+    pub bus: u8,
+    pub period: Duration,
+    /// Number of samples in the gyroscope calibration and rotation window
+    pub samples: usize,
+    /// Degrees the unit has to turn about any axis within the window, integrated from the
+    /// filtered gyroscope rates, to trigger a magnetometer recalibration. The default of 36 is
+    /// what the original per-sample sum of rates against 360 amounted to at the 100 ms period
+    /// it was tuned for. The check only runs once the gyroscope bias is calibrated.
+    pub recal_rotation: f32,
+    pub calib_path: PathBuf,
 }
 
 #[derive(Debug)]
 pub struct Settings {
-    pub gps: UartDeviceSettings,
+    pub gps: GpsSettings,
     pub aht_bus: u8,
-    pub wind: UartDeviceSettings,
+    pub wind: WindSettings,
     pub imu_bus: u8,
+    pub imu_period: Duration,
+    /// Span of the gyroscope calibration and rotation window
+    pub imu_window: Duration,
+    /// See `ImuSettings::recal_rotation`
+    pub imu_recal_rotation: f32,
+    pub bmp: BmpSettings,
+    /// Recalibrate the BMP280 altitude reference from the GPS altitude while there is a fix,
+    /// so the barometric altitude follows weather changes and stays usable without one
+    pub bmp_gps_reference: bool,
+    pub temp_window: usize,
+    /// Consecutive failed data file writes after which storage is reported as failing
+    pub storage_failures: u32,
+    /// Post data to the publish endpoint, otherwise it's only written locally
+    pub publish: bool,
+    pub publish_url: String,
+    /// Number of messages waiting to be published before the oldest ones are dropped
+    pub publish_queue: usize,
+    pub publish_connect_timeout: Duration,
+    /// Limit on a whole publish request, so a half-open connection fails fast
+    pub publish_timeout: Duration,
+    /// Size of the on-disk spool keeping messages while the publish endpoint is unreachable,
+    /// they are dropped when unset
+    pub publish_spool_bytes: Option<u64>,
+    /// Runs the INA219 uncalibrated when unset
+    pub ina_calibration: Option<ina::Calibration>,
+    /// How long both capture paths have to report no data before an audio fault is raised
+    pub audio_fault_after: Duration,
+    /// Latitude, longitude and altitude reported while the GPS has no fix
+    pub fixed_location: Option<(f64, f64, f32)>,
+    /// Free space in GiB below which no new data or audio files are started
+    pub min_free_gb: f32,
+    /// Number of newest data files kept, older ones are deleted; all are kept when unset
+    pub data_files_kept: Option<usize>,
+    /// Append cycles to rolling ndjson files instead of writing a file per cycle
+    pub ndjson: Option<NdjsonSettings>,
+    /// Also append every cycle to `sensors.csv`
+    pub csv_export: bool,
+    /// Also publish every cycle to an MQTT broker
+    pub mqtt: Option<MqttSettings>,
+    /// Port serving the latest statuses on `GET /status`, no server runs when unset
+    pub status_port: Option<u16>,
+    /// Consecutive failed reads after which the IMU, wind sensor and INA219 are initialized
+    /// again
+    pub reinit_after: usize,
 }
 
 impl Default for Settings {
     fn default() -> Self {
-        let gps = UartDeviceSettings {
-            port: "/dev/ttyAMA0",
-            baud_rate: 9_600,
-            timeout: Duration::from_millis(250),
+        let gps = GpsSettings {
+            uart: UartDeviceSettings {
+                port: "/dev/ttyAMA0".to_owned(),
+                baud_rate: 9_600,
+                timeout: Duration::from_millis(250),
+            },
+            outlier: OutlierGate {
+                distance: 500.0,
+                limit: 5,
+            },
         };
         let aht_bus = 1u8;
-        let wind = UartDeviceSettings {
-            port: "/dev/ttyAMA2",
-            baud_rate: 9_600,
-            timeout: Duration::from_millis(250),
+        let wind = WindSettings {
+            uart: UartDeviceSettings {
+                port: "/dev/ttyAMA2".to_owned(),
+                baud_rate: 9_600,
+                timeout: Duration::from_millis(250),
+            },
+            scaling: WindScaling::default(),
         };
         let imu_bus = 1u8;
-        Self { gps, aht_bus, wind, imu_bus }
+        let imu_period = Duration::from_millis(100);
+        let imu_window = Duration::from_secs(10);
+        let imu_recal_rotation = 36.0;
+        let bmp = BmpSettings {
+            window: 5,
+            ground_pressure: Bmp::STANDARD_PRESSURE,
+        };
+        let bmp_gps_reference = false;
+        let temp_window = 6;
+        let storage_failures = 3;
+        let publish = true;
+        let publish_url = "http://mlynarczyk.edu.pl:8080/andros/publish".to_owned();
+        let publish_queue = 12;
+        let publish_connect_timeout = Duration::from_secs(3);
+        let publish_timeout = Duration::from_secs(5);
+        let publish_spool_bytes = Some(16 * 1024 * 1024);
+        let ina_calibration = None;
+        let audio_fault_after = Duration::from_secs(30);
+        let fixed_location = None;
+        let min_free_gb = 1.0;
+        let data_files_kept = None;
+        let ndjson = None;
+        let csv_export = false;
+        let mqtt = None;
+        let status_port = None;
+        let reinit_after = 10;
+        Self {
+            gps,
+            aht_bus,
+            wind,
+            imu_bus,
+            imu_period,
+            imu_window,
+            imu_recal_rotation,
+            bmp,
+            bmp_gps_reference,
+            temp_window,
+            storage_failures,
+            publish,
+            publish_url,
+            publish_queue,
+            publish_connect_timeout,
+            publish_timeout,
+            publish_spool_bytes,
+            ina_calibration,
+            audio_fault_after,
+            fixed_location,
+            min_free_gb,
+            data_files_kept,
+            ndjson,
+            csv_export,
+            mqtt,
+            status_port,
+            reinit_after,
+        }
     }
 }
@@ -1,11 +1,77 @@
-use std::io::BufRead;
 use std::time::Duration;
 
 use chrono::NaiveTime;
-use rppal::uart::{Parity, Uart};
+use log::warn;
+use nmea::sentences::{FixType, RmcStatusOfFix};
+use rppal::uart::{Parity, Queue};
+#[cfg(not(feature = "mock-hardware"))]
+use rppal::uart::Uart;
 use serde::{Deserialize, Serialize};
 
-use super::Device;
+use super::device_manager::{GpsSettings, UartDeviceSettings};
+use super::{out_of_range, Device};
+#[cfg(feature = "mock-hardware")]
+use crate::mock::Uart;
+
+/// Rejects fixes that jump further than `distance` meters from the running average of recent
+/// fixes. After `limit` consecutive rejections the receiver is assumed to have actually moved
+/// and the new position is accepted.
+#[derive(Debug, Clone, Copy)]
+pub struct OutlierGate {
+    pub distance: f64,
+    pub limit: u32,
+}
+
+/// Running average of recent fixes that `OutlierGate` compares new ones against. It lives
+/// outside `Gps`, so the history survives the receiver being initialized again.
+#[derive(Debug, Default)]
+pub struct OutlierFilter {
+    average: Option<(f64, f64)>,
+    outliers: u32,
+}
+
+impl OutlierFilter {
+    /// Weight of a new fix in the running average position
+    const AVERAGE_WEIGHT: f64 = 0.2;
+    const EARTH_RADIUS: f64 = 6_371_000.0;
+
+    /// Great-circle distance in meters between two points given in degrees
+    fn distance((lat1, lon1): (f64, f64), (lat2, lon2): (f64, f64)) -> f64 {
+        let (lat1, lat2) = (lat1.to_radians(), lat2.to_radians());
+        let d_lat = lat2 - lat1;
+        let d_lon = (lon2 - lon1).to_radians();
+        let a = (d_lat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (d_lon / 2.0).sin().powi(2);
+        2.0 * Self::EARTH_RADIUS * a.sqrt().asin()
+    }
+
+    /// Returns `Error::Outlier` if `data` is rejected by `gate`, otherwise adds it to the
+    /// average.
+    pub fn check(&mut self, gate: &OutlierGate, data: &Data) -> Result<(), Error> {
+        let position = (data.latitude, data.longitude);
+        let Some(average) = self.average else {
+            self.average = Some(position);
+            return Ok(());
+        };
+
+        let distance = Self::distance(average, position);
+        if distance > gate.distance {
+            self.outliers += 1;
+            if self.outliers < gate.limit {
+                return Err(Error::Outlier(distance));
+            }
+            warn!("GPS accepted new position {distance:.0} m away after {} outliers", self.outliers);
+            self.average = Some(position);
+        } else {
+            let w = Self::AVERAGE_WEIGHT;
+            self.average = Some((
+                (1.0 - w) * average.0 + w * position.0,
+                (1.0 - w) * average.1 + w * position.1,
+            ));
+        }
+        self.outliers = 0;
+        Ok(())
+    }
+}
 
 #[derive(Debug)]
 pub struct Gps {
@@ -20,12 +86,112 @@ impl Gps {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Extracts a fix from the last valid GGA sentence in a chunk of NMEA text, along with the
+/// motion from the last valid RMC sentence if there is one.
+///
+/// Sentences failing their `*XX` checksum, or missing it, are never parsed, noise on the line
+/// can otherwise turn into plausible but wrong coordinates. A valid GGA sentence wins over any
+/// number of corrupt ones; if every GGA sentence is corrupt the chunk is reported as
+/// `Error::InvalidNmeaString`, without any GGA sentence or without a fix as `Error::NoData`.
+pub fn parse_sentences(text: &str) -> Result<Data, Error> {
+    let (lines, corrupt): (Vec<&str>, Vec<&str>) = text.lines().partition(|l| checksum_valid(l));
+
+    let Some(line) = lines.iter().rfind(|l| l.starts_with("$GPGGA")) else {
+        if corrupt.iter().any(|l| l.starts_with("$GPGGA")) {
+            return Err(Error::InvalidNmeaString);
+        }
+        return Err(Error::NoData);
+    };
+    let motion = lines
+        .iter()
+        .rfind(|l| l.starts_with("$GPRMC"))
+        .and_then(|l| parse_motion(l));
+
+    let Ok(data) = nmea::parse_str(line) else {
+        return Err(Error::InvalidNmeaString);
+    };
+
+    match data {
+        nmea::ParseResult::GGA(d) => {
+            let Some(longitude) = d.longitude else {
+                return Err(Error::NoData);
+            };
+            let Some(latitude) = d.latitude else {
+                return Err(Error::NoData);
+            };
+            let Some(timestamp) = d.fix_time else {
+                return Err(Error::NoData);
+            };
+            let fix_quality = d.fix_type.map_or(0, fix_quality);
+            if fix_quality == 0 {
+                return Err(Error::NoData);
+            }
+            let satellites = d.fix_satellites.map_or(0, |n| n.try_into().unwrap_or(u8::MAX));
+            // A 2D fix without altitude is still a usable position
+            let altitude = d.altitude;
+            Ok(Data {
+                longitude,
+                latitude,
+                altitude,
+                timestamp,
+                fix_quality,
+                satellites,
+                speed_knots: motion.and_then(|(speed, _)| speed),
+                course: motion.and_then(|(_, course)| course),
+                configured: false,
+            })
+        }
+        _ => Err(Error::InvalidNmeaString),
+    }
+}
+
+/// Speed over ground and course from an RMC sentence, `None` if it has no valid fix.
+fn parse_motion(line: &str) -> Option<(Option<f32>, Option<f32>)> {
+    match nmea::parse_str(line) {
+        Ok(nmea::ParseResult::RMC(d)) if d.status_of_fix != RmcStatusOfFix::Invalid => {
+            Some((d.speed_over_ground, d.true_course))
+        }
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Data {
-    longitude: f64,
-    latitude: f64,
-    altitude: f32,
+    pub longitude: f64,
+    pub latitude: f64,
+    /// Meters above mean sea level
+    pub altitude: Option<f32>,
     timestamp: NaiveTime,
+    /// GGA fix quality, 1 for a GPS fix, 2 for DGPS and so on
+    pub fix_quality: u8,
+    /// Satellites used in the fix
+    pub satellites: u8,
+    /// Speed over ground from RMC, `None` without a valid RMC sentence in the same read
+    pub speed_knots: Option<f32>,
+    /// True course over ground in degrees from RMC
+    pub course: Option<f32>,
+    /// Position comes from `Settings::fixed_location` rather than a fix
+    configured: bool,
+}
+
+impl Data {
+    /// GGA fix quality of a manually entered position
+    const MANUAL_FIX: u8 = 7;
+
+    /// A surveyed-in position reported in place of a fix.
+    pub fn fixed((latitude, longitude, altitude): (f64, f64, f32)) -> Self {
+        Self {
+            longitude,
+            latitude,
+            altitude: Some(altitude),
+            timestamp: chrono::Utc::now().time(),
+            fix_quality: Self::MANUAL_FIX,
+            satellites: 0,
+            speed_knots: None,
+            course: None,
+            configured: true,
+        }
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -42,67 +208,227 @@ pub enum Error {
     NoData,
     #[error("Invalid NMEA string")]
     InvalidNmeaString,
+    #[error("Rejected outlier fix {0:.0} m from recent average")]
+    Outlier(f64),
+}
+
+/// Numeric GGA fix quality, 0 being an invalid fix.
+fn fix_quality(fix_type: FixType) -> u8 {
+    match fix_type {
+        FixType::Invalid => 0,
+        FixType::Gps => 1,
+        FixType::DGps => 2,
+        FixType::Pps => 3,
+        FixType::Rtk => 4,
+        FixType::FloatRtk => 5,
+        FixType::Estimated => 6,
+        FixType::Manual => 7,
+        FixType::Simulation => 8,
+    }
+}
+
+/// The part of `buf` filled by a UART read of `bytes`, with `queued` bytes still waiting after
+/// it.
+///
+/// A read filling the whole buffer while more bytes are queued means we fell behind the
+/// receiver, and is reported as `Error::DataOverflow` so the caller discards the queue and the
+/// next read starts from fresh data. A read filling the buffer exactly with nothing queued is
+/// complete.
+fn received(buf: &[u8], bytes: usize, queued: usize) -> Result<&[u8], Error> {
+    if bytes >= buf.len() && queued > 0 {
+        return Err(Error::DataOverflow);
+    }
+    Ok(&buf[..bytes.min(buf.len())])
+}
+
+/// Verifies the `*XX` checksum of an NMEA sentence, which is the XOR of all bytes between
+/// the leading `$` and the `*`.
+fn checksum_valid(sentence: &str) -> bool {
+    let Some(sentence) = sentence.trim_end().strip_prefix('$') else {
+        return false;
+    };
+    let Some((body, checksum)) = sentence.split_once('*') else {
+        return false;
+    };
+    let Ok(checksum) = u8::from_str_radix(checksum, 16) else {
+        return false;
+    };
+    body.bytes().fold(0, |acc, b| acc ^ b) == checksum
 }
 
 impl Device for Gps {
     type Data = Data;
     type Error = Error;
+    type Settings = GpsSettings;
+
+    fn init(settings: &Self::Settings) -> Result<Self, Self::Error> {
+        let UartDeviceSettings { port, baud_rate, timeout } = &settings.uart;
+        Self::new(port, *baud_rate, *timeout)
+    }
+
+    fn implausible(data: &Self::Data) -> Option<String> {
+        out_of_range("latitude", data.latitude, -90.0..=90.0)
+            .or_else(|| out_of_range("longitude", data.longitude, -180.0..=180.0))
+    }
+
     fn get_data(&mut self) -> Result<Self::Data, Self::Error> {
         let mut buf = [0u8; 8192];
 
         let bytes = self.device.read(&mut buf)?;
-        if bytes == 1024 && self.device.input_len()? > 0 {
-            return Err(Error::DataOverflow);
-        }
+        // A shorter read already emptied the queue
+        let queued = if bytes == buf.len() { self.device.input_len()? } else { 0 };
+        let received = match received(&buf, bytes, queued) {
+            Ok(received) => received,
+            Err(err) => {
+                self.device.flush(Queue::Input)?;
+                return Err(err);
+            }
+        };
 
-        let lines = buf.lines();
+        // Bytes garbled into invalid UTF-8 fail the checksum of their line
+        parse_sentences(&String::from_utf8_lossy(received))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The sentence the mock UART replays
+    const GGA: &str = "$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*47";
+    /// `GGA` with the fix time changed and the checksum left as is
+    const GGA_BAD_CHECKSUM: &str =
+        "$GPGGA,123520,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*47";
+
+    #[test]
+    fn checksum() {
+        assert!(checksum_valid(GGA));
+        assert!(checksum_valid(&format!("{GGA}\r")));
+        assert!(!checksum_valid(GGA_BAD_CHECKSUM));
+        assert!(!checksum_valid(GGA.trim_end_matches("*47")));
+        assert!(!checksum_valid(&GGA.replace("*47", "*4")));
+        assert!(!checksum_valid(&GGA[1..]));
+    }
 
-        //eprintln!("{lines:?}");
+    #[test]
+    fn valid_gga() {
+        let data = parse_sentences(&format!("{GGA}\r\n")).unwrap();
+        assert!((data.latitude - (48.0 + 7.038 / 60.0)).abs() < 1e-9);
+        assert!((data.longitude - (11.0 + 31.0 / 60.0)).abs() < 1e-9);
+        assert_eq!(data.altitude, Some(545.4));
+        assert_eq!(data.timestamp, NaiveTime::from_hms_opt(12, 35, 19).unwrap());
+        assert_eq!(data.fix_quality, 1);
+        assert_eq!(data.satellites, 8);
+        assert_eq!(data.speed_knots, None);
+        assert!(!data.configured);
+    }
 
-        let gga = lines.filter(|l| {
-            if let Ok(l) = l {
-                //eprintln!("{l}");
-                l.starts_with("$GPGGA")
+    #[test]
+    fn corrupt_checksum() {
+        assert!(matches!(parse_sentences(GGA_BAD_CHECKSUM), Err(Error::InvalidNmeaString)));
+    }
+
+    #[test]
+    fn flipped_byte() {
+        let star = GGA.find('*').unwrap();
+        for i in 1..star {
+            let mut corrupted = GGA.as_bytes().to_vec();
+            corrupted[i] ^= 0x01;
+            let corrupted = String::from_utf8(corrupted).unwrap();
+            assert!(!checksum_valid(&corrupted), "{corrupted}");
+            // A flip in the talker or sentence id leaves no GGA sentence at all
+            let result = parse_sentences(&corrupted);
+            if corrupted.starts_with("$GPGGA") {
+                assert!(matches!(result, Err(Error::InvalidNmeaString)), "{corrupted}");
             } else {
-                false
+                assert!(matches!(result, Err(Error::NoData)), "{corrupted}");
             }
-        });
+        }
+    }
 
-        //eprintln!("{gga:?}");
-        let gga = gga.last();
+    #[test]
+    fn missing_checksum() {
+        let line = GGA.trim_end_matches("*47");
+        assert!(matches!(parse_sentences(line), Err(Error::InvalidNmeaString)));
+    }
 
-        //eprintln!("{gga:?}");
+    #[test]
+    fn no_gga() {
+        assert!(matches!(parse_sentences(""), Err(Error::NoData)));
+        let gsa = "$GPGSA,A,3,04,05,,09,12,,,24,,,,,2.5,1.3,2.1*39";
+        assert!(matches!(parse_sentences(gsa), Err(Error::NoData)));
+    }
 
-        let Some(Ok(line)) = gga else {
-            return Err(Error::NoData);
-        };
+    #[test]
+    fn canned_read() {
+        // A read starting mid-sentence, as UART reads rarely line up with sentence boundaries
+        let buf: &[u8] = b"4,,,,,2.5,1.3,2.1*39\r\n\
+            $GPGSV,1,1,02,04,77,101,42,05,45,240,38*70\r\n\
+            $GPRMC,123519,A,4807.038,N,01131.000,E,022.4,084.4,230394,003.1,W*6A\r\n\
+            $GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*47\r\n\
+            $GPGSA,A,3,04";
+        let data = parse_sentences(&String::from_utf8_lossy(buf)).unwrap();
+        assert_eq!(data.satellites, 8);
+        assert_eq!(data.speed_knots, Some(22.4));
+        assert_eq!(data.course, Some(84.4));
+    }
 
-        let Ok(data) = nmea::parse_str(line.as_str()) else {
-            return Err(Error::InvalidNmeaString);
-        };
+    #[test]
+    fn short_read() {
+        // Leftovers of an earlier, longer read past the bytes of this one. Being the later
+        // valid GGA, they would win if they were parsed.
+        let mut buf = [0u8; 8192];
+        let stale = b"$GPGGA,000001,5213.000,N,02100.000,E,1,04,1.0,100.0,M,0.0,M,,*71\r\n";
+        let line = format!("{GGA}\r\n");
+        buf[..line.len()].copy_from_slice(line.as_bytes());
+        buf[line.len()..line.len() + stale.len()].copy_from_slice(stale);
 
-        match data {
-            nmea::ParseResult::GGA(d) => {
-                let Some(longitude) = d.longitude else {
-                    return Err(Error::NoData);
-                };
-                let Some(latitude) = d.latitude else {
-                    return Err(Error::NoData);
-                };
-                let Some(timestamp) = d.fix_time else {
-                    return Err(Error::NoData);
-                };
-                let Some(altitude) = d.altitude else {
-                    return Err(Error::NoData);
-                };
-                Ok(Self::Data {
-                    longitude,
-                    latitude,
-                    altitude,
-                    timestamp,
-                })
-            }
-            _ => Err(Error::InvalidNmeaString),
-        }
+        let chunk = received(&buf, line.len(), 0).unwrap();
+        assert_eq!(chunk, line.as_bytes());
+        let data = parse_sentences(&String::from_utf8_lossy(chunk)).unwrap();
+        assert_eq!(data.timestamp, NaiveTime::from_hms_opt(12, 35, 19).unwrap());
+    }
+
+    #[test]
+    fn overflow() {
+        let mut buf = [b'x'; 8192];
+        let line = format!("{GGA}\r\n");
+        buf[..line.len()].copy_from_slice(line.as_bytes());
+        assert!(matches!(received(&buf, buf.len(), 1), Err(Error::DataOverflow)));
+        // Exactly full with nothing left behind is a complete read
+        assert_eq!(received(&buf, buf.len(), 0).unwrap().len(), buf.len());
+    }
+
+    #[test]
+    fn mixed_lines() {
+        // A corrupt GGA after a valid one doesn't hide the valid fix
+        let text = format!("{GGA}\r\n{GGA_BAD_CHECKSUM}\r\n$GPGGA,1235\r\n");
+        let data = parse_sentences(&text).unwrap();
+        assert_eq!(data.timestamp, NaiveTime::from_hms_opt(12, 35, 19).unwrap());
+
+        let text = format!("{GGA_BAD_CHECKSUM}\r\n{GGA}\r\n");
+        assert!(parse_sentences(&text).is_ok());
+    }
+
+    #[test]
+    fn outlier_filter() {
+        let gate = OutlierGate {
+            distance: 500.0,
+            limit: 3,
+        };
+        let fix = parse_sentences(GGA).unwrap();
+        // About 1.1 km north
+        let jumped = Data {
+            latitude: fix.latitude + 0.01,
+            ..fix.clone()
+        };
+        let mut filter = OutlierFilter::default();
+        assert!(filter.check(&gate, &fix).is_ok());
+        assert!(filter.check(&gate, &fix).is_ok());
+        assert!(matches!(filter.check(&gate, &jumped), Err(Error::Outlier(d)) if d > 1000.0));
+        assert!(filter.check(&gate, &jumped).is_err());
+        // The receiver has moved
+        assert!(filter.check(&gate, &jumped).is_ok());
+        assert!(filter.check(&gate, &jumped).is_ok());
     }
 }
@@ -1,78 +1,39 @@
-use std::f32::consts::PI;
 use std::fmt::Debug;
 use std::fs::File;
 use std::io::{BufReader, BufWriter};
 use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
+use chrono::{DateTime, Utc};
 use log::{debug, info};
 use mpu9250::{Mpu9250, MpuConfig};
 use serde::{Deserialize, Serialize};
 
+use super::circular_buffer::CircularVector;
+use super::device_manager::ImuSettings;
 use super::Device;
 
-trait Buffer {
-    type Container;
-}
-
-impl<T> Buffer for Vec<T> {
-    type Container = Vec<T>;
-}
-
-struct CircularBuffer<B: Buffer> {
-    size: usize,
-    buf: B::Container,
-    index: usize,
-}
-
-impl<B: Buffer> CircularBuffer<B> {
-    fn increment_index(&mut self) {
-        self.index += 1;
-        self.index %= self.size;
-    }
-}
-
-impl<T: Clone + Copy> CircularBuffer<Vec<T>> {
-    fn new(size: usize, fill: T) -> Self {
-        Self {
-            size,
-            buf: vec![fill; size],
-            index: 0,
-        }
-    }
+use self::madgwick::Madgwick;
 
-    fn push(&mut self, value: T) {
-        self.buf[self.index] = value;
-        self.increment_index();
-    }
-
-    fn newest(&self) -> T {
-        if self.index == 0 {
-            return self.buf[self.size - 1];
-        }
-        self.buf[self.index - 1]
-    }
+mod ellipsoid;
+mod madgwick;
 
-    fn oldest(&self) -> T {
-        self.buf[self.index]
-    }
+const IDENTITY: [[f32; 3]; 3] = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
 
-    fn reset(&mut self, fill: T) {
-        self.index = 0;
-        self.buf = vec![fill; self.size];
-    }
-
-    //fn iter(&self) -> impl Iterator<Item = &T> {
-    //    //self.buf.iter().skip(self.index).chain(self.buf.iter().take(self.index))
-    //    self.buf.iter().cycle().skip(self.index).take(self.size)
-    //}
-}
-
-type CircularVector<T> = CircularBuffer<Vec<T>>;
-
-#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+/// Magnetometer correction `matrix * ((raw - bias) * scale)`. Files written before the soft
+/// iron matrix was added read with an identity matrix.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
 struct MagCalib {
     bias: [f32; 3],
     scale: [f32; 3],
+    #[serde(default = "MagCalib::identity")]
+    matrix: [[f32; 3]; 3],
+}
+
+impl MagCalib {
+    fn identity() -> [[f32; 3]; 3] {
+        IDENTITY
+    }
 }
 
 #[derive(Serialize, Deserialize, Default, Clone, Copy, Debug)]
@@ -87,6 +48,8 @@ pub struct Imu {
     mag_sens_adj: [f32; 3],
     mag_bias: [f32; 3],
     mag_scale: [f32; 3],
+    /// Soft iron correction
+    mag_matrix: [[f32; 3]; 3],
     gyro_bias: [f32; 3],
     filtered_mag: [f32; 3],
     filtered_acc: [f32; 3],
@@ -96,6 +59,13 @@ pub struct Imu {
     mag_calib_path: PathBuf,
     gyro_calib_path: PathBuf,
     calibrated: bool,
+    ahrs: Madgwick,
+    last_update: Option<Instant>,
+    /// Weight of the previous value in the low-pass filters
+    smoothing: f32,
+    /// Poll period in seconds, the time step the gyroscope rates are integrated over
+    period: f32,
+    recal_rotation: f32,
 }
 
 impl Imu {
@@ -103,9 +73,18 @@ impl Imu {
     //const DEG_TO_RAD: f32 = PI / 180.0;
     const GYRO_SCALE: f32 = 250.0 / 32768.0;
     const MAG_SCALE: f32 = 0.15;
+    /// Die temperature sensitivity in LSB/°C, reading 0 at `TEMP_OFFSET`
+    const TEMP_SENSITIVITY: f32 = 333.87;
+    const TEMP_OFFSET: f32 = 21.0;
     const DEV_CALIB_FILE: &'static str = "calibration";
     const MAG_CALIB_FILE: &'static str = "mag_calibration";
     const GYRO_CALIB_FILE: &'static str = "gyro_calibration";
+    /// Madgwick filter gain, higher values trust the accelerometer and magnetometer more
+    const AHRS_BETA: f32 = 0.1;
+    /// Low-pass filter weight of the previous value at the reference period, tuned for 100 ms
+    const SMOOTHING: f32 = 0.8;
+    const SMOOTHING_PERIOD: Duration = Duration::from_millis(100);
+    const RECAL_ROTATION: f32 = 36.0;
 
     pub fn new(bus: u8, samples: usize, path: &Path) -> Result<Self, Error> {
         let i2c = rppal::i2c::I2c::with_bus(bus)?;
@@ -123,6 +102,7 @@ impl Imu {
             mag_sens_adj: [0.0; 3],
             mag_bias: [0.0; 3],
             mag_scale: [1.0; 3],
+            mag_matrix: IDENTITY,
             gyro_bias: [0.0; 3],
             filtered_mag: [0.0; 3],
             filtered_acc: [0.0; 3],
@@ -132,6 +112,11 @@ impl Imu {
             mag_calib_path: mag_calib_path.clone(),
             gyro_calib_path,
             calibrated: false,
+            ahrs: Madgwick::new(Self::AHRS_BETA),
+            last_update: None,
+            smoothing: Self::SMOOTHING,
+            period: Self::SMOOTHING_PERIOD.as_secs_f32(),
+            recal_rotation: Self::RECAL_ROTATION,
         };
 
         if mag_calib_path.exists() {
@@ -142,6 +127,7 @@ impl Imu {
             info!("MAGNETOMETER CALIBRATION READ FROM FILE");
             s.mag_bias = calib.bias;
             s.mag_scale = calib.scale;
+            s.mag_matrix = calib.matrix;
             info!("MAGNETOMETER CALIBRATION COMPLETED");
         } else {
             info!("MAGNETOMETER CALIBRATION FILE NOT FOUND");
@@ -150,9 +136,56 @@ impl Imu {
         Ok(s)
     }
 
+    /// Adjusts the low-pass filters to a poll period, keeping their time constant the same as
+    /// at the 100 ms they were tuned for, and the time step of the rotation integration.
+    pub fn set_period(&mut self, period: Duration) {
+        self.smoothing = Self::SMOOTHING
+            .powf(period.as_secs_f32() / Self::SMOOTHING_PERIOD.as_secs_f32());
+        self.period = period.as_secs_f32();
+    }
+
+    /// Sets the rotation in degrees about any axis within the gyroscope window that triggers a
+    /// magnetometer recalibration, see `ImuSettings::recal_rotation`.
+    pub fn set_recal_rotation(&mut self, degrees: f32) {
+        self.recal_rotation = degrees;
+    }
+
+    /// Fits the hard and soft iron correction to the samples in the window. Falls back to a
+    /// per-axis bias and scale from their extremes when they don't determine an ellipsoid,
+    /// which happens when the mast was only turned around one axis.
     fn update_mag_calibartion(&mut self) -> Result<(), Error> {
         info!("MAGNETOMETER CALIBRATION START");
 
+        if let Some(fit) = ellipsoid::fit(&self.mag_data.buf) {
+            info!("MAGNETOMETER ELLIPSOID FIT SUCCEEDED");
+            self.mag_bias = fit.bias;
+            self.mag_scale = [1.0; 3];
+            self.mag_matrix = fit.matrix;
+        } else {
+            info!("MAGNETOMETER ELLIPSOID FIT FAILED, USING MIN/MAX");
+            self.update_mag_min_max();
+        }
+
+        info!("WRITING TO MAGNETOMETER CALIBRATION FILE");
+
+        let file = File::create(self.mag_calib_path.clone())?;
+        let mut writer = BufWriter::new(file);
+        serde_json::to_writer(
+            &mut writer,
+            &MagCalib {
+                bias: self.mag_bias,
+                scale: self.mag_scale,
+                matrix: self.mag_matrix,
+            },
+        )?;
+
+        info!("MAGNETOMETER CALIBRATION SAVED TO FILE");
+        info!("MAGNETOMETER CALIBRATION COMPLETED");
+
+        Ok(())
+    }
+
+    fn update_mag_min_max(&mut self) {
         let [mut max_x, mut max_y, mut max_z] = self.mag_data.buf[0];
         let [mut min_x, mut min_y, mut min_z] = self.mag_data.buf[0];
         for &[x, y, z] in self.mag_data.buf.iter().skip(1) {
@@ -183,43 +216,25 @@ impl Imu {
             avg_delta / avg_delta_y,
             avg_delta / avg_delta_z,
         ];
-
-        info!("WRITING TO MAGNETOMETER CALIBRATION FILE");
-
-        let file = File::create(self.mag_calib_path.clone())?;
-        let mut writer = BufWriter::new(file);
-        serde_json::to_writer(
-            &mut writer,
-            &MagCalib {
-                bias: self.mag_bias,
-                scale: self.mag_scale,
-            },
-        )?;
-
-        info!("MAGNETOMETER CALIBRATION SAVED TO FILE");
-        info!("MAGNETOMETER CALIBRATION COMPLETED");
-
-        Ok(())
+        self.mag_matrix = IDENTITY;
     }
 
-    fn dot(v: &[f32; 3], w: &[f32; 3]) -> f32 {
-        v[0] * w[0] + v[1] * w[1] + v[2] * w[2]
-        //v.iter().zip(w.iter()).map(|(x, y)| x * y).sum()
-    }
-
-    /// Orthogonal projection of v on onto the plane orthogonal to w
-    fn oproj(v: &[f32; 3], w: &[f32; 3]) -> [f32; 3] {
-        let a = Self::dot(v, w) / Self::dot(w, w);
-        [v[0] - a * w[0], v[1] - a * w[1], v[2] - a * w[2]]
-    }
-
-    fn calculate_angle(mag: &[f32; 3], acc: &[f32; 3]) -> f32 {
-        // Project mag onto a plane perpendicular to Earth's gravity vector
-        let vec_north = Self::oproj(mag, acc);
-        //let vec_north = mag;
-
-        // Assuming x is forward y is left (or the other way around, directions are hard)
-        vec_north[1].atan2(vec_north[0]) * 180.0 / PI
+    /// Compass heading in degrees clockwise from magnetic north to the sensor x axis, in 0..360.
+    ///
+    /// Both vectors are in the accelerometer frame, right-handed with z up, so `acc` reads
+    /// `[0, 0, 1]` when level. Roll and pitch from `acc` rotate `mag` into the horizontal plane
+    /// first, so the heading holds while the sensor is tilted.
+    fn tilt_compensated_heading(mag: &[f32; 3], acc: &[f32; 3]) -> f32 {
+        let [ax, ay, az] = *acc;
+        let [mx, my, mz] = *mag;
+        let roll = ay.atan2(az);
+        let pitch = (-ax).atan2(ay * roll.sin() + az * roll.cos());
+        let (sin_roll, cos_roll) = roll.sin_cos();
+        let (sin_pitch, cos_pitch) = pitch.sin_cos();
+        let x = mx * cos_pitch + my * sin_pitch * sin_roll + mz * sin_pitch * cos_roll;
+        let y = my * cos_roll - mz * sin_roll;
+        // With y to the left of x, north lies on the positive y side when x points east
+        y.atan2(x).to_degrees().rem_euclid(360.0)
     }
 
     pub fn calibrate(&mut self, try_from_file: bool) -> Result<(), Error> {
@@ -303,7 +318,23 @@ pub struct Data {
     pub acc: [f32; 3],
     gyro: [f32; 3],
     pub mag: [f32; 3],
+    /// Direction of magnetic north in the sensor frame in degrees, derived from `yaw`
     pub angle: f32,
+    /// Fused orientation as a unit quaternion `[w, x, y, z]`
+    pub quat: [f32; 4],
+    /// Fused orientation in degrees
+    pub roll: f32,
+    pub pitch: f32,
+    pub yaw: f32,
+    /// Tilt-compensated compass heading in degrees, `None` until the gyroscope is calibrated
+    pub heading: Option<f32>,
+    /// Degrees turned about each sensor axis over the last `Settings::imu_window`, integrated
+    /// from the gyroscope and independent of the poll rate. Reset to zero whenever the
+    /// gyroscope or magnetometer is recalibrated.
+    pub rotation: [f32; 3],
+    /// Die temperature in °C
+    pub temp: f32,
+    pub timestamp: DateTime<Utc>,
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -332,23 +363,33 @@ impl From<mpu9250::I2CError<rppal::i2c::Error>> for Error {
     }
 }
 
-fn low_pass_filter(a: &[f32; 3], b: &[f32; 3]) -> [f32; 3] {
-    const OLD: f32 = 0.8;
-    const NEW: f32 = 1.0 - OLD;
+fn low_pass_filter(a: &[f32; 3], b: &[f32; 3], old: f32) -> [f32; 3] {
+    let new = 1.0 - old;
     [
-        OLD * a[0] + NEW * b[0],
-        OLD * a[1] + NEW * b[1],
-        OLD * a[2] + NEW * b[2],
+        old * a[0] + new * b[0],
+        old * a[1] + new * b[1],
+        old * a[2] + new * b[2],
     ]
 }
 
 impl Device for Imu {
     type Data = Data;
     type Error = Error;
+    type Settings = ImuSettings;
+
+    fn init(settings: &Self::Settings) -> Result<Self, Self::Error> {
+        let mut imu = Self::new(settings.bus, settings.samples, &settings.calib_path)?;
+        imu.set_period(settings.period);
+        imu.set_recal_rotation(settings.recal_rotation);
+        imu.calibrate(true)?;
+        Ok(imu)
+    }
 
     fn get_data(&mut self) -> Result<Self::Data, Self::Error> {
         match self.device.unscaled_all::<[i16; 3]>() {
             Ok(data) => {
+                let timestamp = Utc::now();
+                let temp = f32::from(data.temp) / Self::TEMP_SENSITIVITY + Self::TEMP_OFFSET;
                 let mag = [
                     f32::from(data.mag[0]) * Self::MAG_SCALE * self.mag_sens_adj[0],
                     f32::from(data.mag[1]) * Self::MAG_SCALE * self.mag_sens_adj[1],
@@ -369,7 +410,7 @@ impl Device for Imu {
 
                 debug!("gyro: {gyro:?}");
                 if self.calibrated {
-                    self.filtered_gyro = low_pass_filter(&self.filtered_gyro, &gyro);
+                    self.filtered_gyro = low_pass_filter(&self.filtered_gyro, &gyro, self.smoothing);
                     self.gyro_data.push(self.filtered_gyro);
                 } else {
                     self.gyro_data.push(gyro);
@@ -380,14 +421,33 @@ impl Device for Imu {
                     (mag[1] - self.mag_bias[1]) * self.mag_scale[1],
                     (mag[2] - self.mag_bias[2]) * self.mag_scale[2],
                 ];
-
-                self.filtered_acc = low_pass_filter(&self.filtered_acc, &acc);
-                self.filtered_mag = low_pass_filter(&self.filtered_mag, &mag);
-
-                let mut angle = Self::calculate_angle(&self.filtered_mag, &self.filtered_acc);
-                if angle < 0.0 {
-                    angle += 360.0;
+                let mag = self
+                    .mag_matrix
+                    .map(|row| row[0] * mag[0] + row[1] * mag[1] + row[2] * mag[2]);
+
+                self.filtered_acc = low_pass_filter(&self.filtered_acc, &acc, self.smoothing);
+                self.filtered_mag = low_pass_filter(&self.filtered_mag, &mag, self.smoothing);
+
+                let now = Instant::now();
+                if let Some(last_update) = self.last_update {
+                    self.ahrs.update(
+                        self.filtered_gyro.map(f32::to_radians),
+                        self.filtered_acc,
+                        self.filtered_mag,
+                        now.duration_since(last_update).as_secs_f32(),
+                    );
                 }
+                self.last_update = Some(now);
+
+                let [roll, pitch, yaw] = self.ahrs.euler();
+                // Direction of magnetic north in the sensor frame, opposite to the yaw of the
+                // sensor relative to north
+                let angle = (-yaw).rem_euclid(360.0);
+                // The magnetometer is only trustworthy once the gyroscope bias is known and the
+                // rotation check can recalibrate it
+                let heading = self.calibrated.then(|| {
+                    Self::tilt_compensated_heading(&self.filtered_mag, &self.filtered_acc)
+                });
 
                 //eprintln!(
                 //    "angle: {angle}  |  acc: {:?}  |  mag: {:?}",
@@ -426,21 +486,98 @@ impl Device for Imu {
                 let newest = self.gyro_data.newest();
                 let oldest = self.gyro_data.oldest();
 
-                self.rotation[0] += newest[0] - oldest[0];
-                self.rotation[1] += newest[1] - oldest[1];
-                self.rotation[2] += newest[2] - oldest[2];
+                // Running sum of the rates in the window times the nominal period, so the
+                // rotation doesn't scale with the poll rate. The measured interval would jitter
+                // and keep the rates added and later removed from cancelling out.
+                for (i, rotation) in self.rotation.iter_mut().enumerate() {
+                    *rotation += (newest[i] - oldest[i]) * self.period;
+                }
 
                 debug!("rotation: {:?}", self.rotation);
 
-                if self.calibrated && self.rotation.iter().any(|r| r.abs() >= 360.0) {
+                // The magnetometer fit needs readings from many headings. Once the unit turned
+                // by `recal_rotation` degrees about any axis within the window, new headings
+                // have entered the magnetometer window and the fit is redone from it. Slow drift
+                // or vibration never adds up to that much within the window, since rates
+                // leaving the window are subtracted again.
+                if self.calibrated
+                    && self.rotation.iter().any(|r| r.abs() >= self.recal_rotation)
+                {
                     self.update_mag_calibartion()?;
                     self.rotation = [0.0; 3];
                     self.gyro_data.reset([0.0; 3]);
                 }
 
-                Ok(Self::Data { acc, gyro, mag, angle })
+                Ok(Self::Data {
+                    acc,
+                    gyro,
+                    mag,
+                    angle,
+                    quat: self.ahrs.quaternion(),
+                    roll,
+                    pitch,
+                    yaw,
+                    heading,
+                    rotation: self.rotation,
+                    temp,
+                    timestamp,
+                })
             }
             Err(e) => Err(Error::Bus(e)),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Horizontal and downward components of the earth field in µT, about those in Poland
+    const FIELD: (f32, f32) = (19.0, 46.0);
+
+    /// Magnetometer and accelerometer readings of a sensor with its x axis at `heading` degrees
+    /// clockwise from north, pitched by `pitch` and rolled by `roll` degrees.
+    fn readings(heading: f32, pitch: f32, roll: f32) -> ([f32; 3], [f32; 3]) {
+        // Sensor to world rotation, the world frame being north, west, up
+        let (s, c) = (-heading).to_radians().sin_cos();
+        let yaw = [[c, -s, 0.0], [s, c, 0.0], [0.0, 0.0, 1.0]];
+        let (s, c) = pitch.to_radians().sin_cos();
+        let pitch = [[c, 0.0, s], [0.0, 1.0, 0.0], [-s, 0.0, c]];
+        let (s, c) = roll.to_radians().sin_cos();
+        let roll = [[1.0, 0.0, 0.0], [0.0, c, -s], [0.0, s, c]];
+        let mul = |a: [[f32; 3]; 3], b: [[f32; 3]; 3]| {
+            a.map(|row| [0, 1, 2].map(|j| (0..3).map(|k| row[k] * b[k][j]).sum()))
+        };
+        let r = mul(mul(yaw, pitch), roll);
+        // World vectors into the sensor frame by the transposed rotation
+        let to_sensor = |v: [f32; 3]| [0, 1, 2].map(|i| (0..3).map(|k| r[k][i] * v[k]).sum());
+        (to_sensor([FIELD.0, 0.0, -FIELD.1]), to_sensor([0.0, 0.0, 1.0]))
+    }
+
+    fn angle_between(a: f32, b: f32) -> f32 {
+        let d = (a - b).rem_euclid(360.0);
+        d.min(360.0 - d)
+    }
+
+    #[test]
+    fn level() {
+        for expected in [0.0, 90.0, 180.0, 270.0] {
+            let (mag, acc) = readings(expected, 0.0, 0.0);
+            let heading = Imu::tilt_compensated_heading(&mag, &acc);
+            assert!(angle_between(heading, expected) < 0.01, "{heading} != {expected}");
+        }
+    }
+
+    #[test]
+    fn tilted() {
+        for (expected, pitch, roll) in [(90.0, 20.0, 0.0), (200.0, -20.0, 30.0)] {
+            let (mag, acc) = readings(expected, pitch, roll);
+            let heading = Imu::tilt_compensated_heading(&mag, &acc);
+            assert!(angle_between(heading, expected) < 0.01, "{heading} != {expected}");
+
+            // Taking the raw horizontal components is far off at this tilt
+            let uncompensated = mag[1].atan2(mag[0]).to_degrees().rem_euclid(360.0);
+            assert!(angle_between(uncompensated, expected) > 10.0, "{uncompensated}");
+        }
+    }
+}
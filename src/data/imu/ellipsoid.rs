@@ -0,0 +1,278 @@
+/// Hard and soft iron correction fitted to magnetometer samples, the corrected field is
+/// `matrix * (raw - bias)`.
+#[derive(Debug, Clone, Copy)]
+pub struct Fit {
+    pub bias: [f32; 3],
+    pub matrix: [[f32; 3]; 3],
+}
+
+/// Largest ratio between the longest and shortest ellipsoid axis accepted as a fit. Samples
+/// that don't cover enough headings, e.g. from turning around a single axis, give degenerate
+/// ellipsoids far past it.
+const MAX_AXIS_RATIO: f64 = 2.0;
+
+/// Fits the ellipsoid `x'Ax + 2v'x = 1` to `samples` by least squares and returns the
+/// correction mapping it onto a sphere of the same mean radius. Returns `None` if the samples
+/// don't determine an ellipsoid, e.g. when they all lie on a plane.
+pub fn fit(samples: &[[f32; 3]]) -> Option<Fit> {
+    if samples.len() < 9 {
+        return None;
+    }
+
+    // Centering and scaling the samples to about unit size keeps the normal equations well
+    // conditioned
+    #[allow(clippy::cast_precision_loss)]
+    let n = samples.len() as f64;
+    let mut mean = [0.0; 3];
+    for sample in samples {
+        for (m, &x) in mean.iter_mut().zip(sample) {
+            *m += f64::from(x) / n;
+        }
+    }
+    let scale = samples
+        .iter()
+        .map(|s| (0..3).map(|i| (f64::from(s[i]) - mean[i]).powi(2)).sum::<f64>().sqrt())
+        .sum::<f64>()
+        / n;
+    if scale == 0.0 || !scale.is_finite() {
+        return None;
+    }
+
+    let mut ata = [[0.0; 9]; 9];
+    let mut atb = [0.0; 9];
+    for sample in samples {
+        let [x, y, z] = [0, 1, 2].map(|i| (f64::from(sample[i]) - mean[i]) / scale);
+        let row = [
+            x * x,
+            y * y,
+            z * z,
+            2.0 * x * y,
+            2.0 * x * z,
+            2.0 * y * z,
+            2.0 * x,
+            2.0 * y,
+            2.0 * z,
+        ];
+        for i in 0..9 {
+            for j in 0..9 {
+                ata[i][j] += row[i] * row[j];
+            }
+            atb[i] += row[i];
+        }
+    }
+    let p = solve(ata, atb)?;
+
+    let a = [[p[0], p[3], p[4]], [p[3], p[1], p[5]], [p[4], p[5], p[2]]];
+    let v = [p[6], p[7], p[8]];
+    // Substituting x = y + c with c = -inv(A)v leaves y'Ay = 1 + c'Ac
+    let c = solve(a, v)?.map(|x| -x);
+    let k = 1.0 + dot(&c, &mat_vec(&a, &c));
+    if k <= 0.0 {
+        return None;
+    }
+    let a = a.map(|row| row.map(|x| x / k));
+
+    let (values, vectors) = eigen(a);
+    if values.iter().any(|&l| l <= 0.0) {
+        return None;
+    }
+    let max = values.iter().copied().fold(f64::MIN, f64::max);
+    let min = values.iter().copied().fold(f64::MAX, f64::min);
+    if (max / min).sqrt() > MAX_AXIS_RATIO {
+        return None;
+    }
+
+    // The square root of A maps the ellipsoid onto the unit sphere, scaled back to the
+    // geometric mean of the semi-axes. Its unit is the one of the scaled samples, but since
+    // x - bias is scaled by the same factor the matrix applies to the raw samples unchanged.
+    let radius = (values[0] * values[1] * values[2]).powf(-1.0 / 6.0);
+    let sqrt = values.map(f64::sqrt);
+    let mut matrix = [[0.0; 3]; 3];
+    for (i, row) in matrix.iter_mut().enumerate() {
+        for (j, m) in row.iter_mut().enumerate() {
+            let w: f64 = (0..3).map(|e| vectors[i][e] * sqrt[e] * vectors[j][e]).sum();
+            #[allow(clippy::cast_possible_truncation)]
+            let w = (radius * w) as f32;
+            *m = w;
+        }
+    }
+    #[allow(clippy::cast_possible_truncation)]
+    let bias = [0, 1, 2].map(|i| (mean[i] + c[i] * scale) as f32);
+    // Never hand out a correction that would turn every later reading into NaN
+    if !bias.iter().chain(matrix.iter().flatten()).all(|x| x.is_finite()) {
+        return None;
+    }
+    Some(Fit { bias, matrix })
+}
+
+fn dot(a: &[f64; 3], b: &[f64; 3]) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn mat_vec(m: &[[f64; 3]; 3], v: &[f64; 3]) -> [f64; 3] {
+    m.map(|row| dot(&row, v))
+}
+
+/// Solves `m * x = b` by Gaussian elimination with partial pivoting, `None` if `m` is
+/// singular.
+fn solve<const N: usize>(mut m: [[f64; N]; N], mut b: [f64; N]) -> Option<[f64; N]> {
+    let magnitude = m.iter().flatten().fold(0.0, |acc: f64, x| acc.max(x.abs()));
+    for col in 0..N {
+        let pivot = (col..N).max_by(|&i, &j| m[i][col].abs().total_cmp(&m[j][col].abs()))?;
+        if m[pivot][col].abs() <= magnitude * 1e-12 {
+            return None;
+        }
+        m.swap(col, pivot);
+        b.swap(col, pivot);
+        let (upper, lower) = m.split_at_mut(col + 1);
+        let pivot_row = &upper[col];
+        for (i, row) in lower.iter_mut().enumerate() {
+            let factor = row[col] / pivot_row[col];
+            for (x, p) in row[col..].iter_mut().zip(&pivot_row[col..]) {
+                *x -= factor * p;
+            }
+            b[col + 1 + i] -= factor * b[col];
+        }
+    }
+    let mut x = [0.0; N];
+    for row in (0..N).rev() {
+        let sum: f64 = (row + 1..N).map(|k| m[row][k] * x[k]).sum();
+        x[row] = (b[row] - sum) / m[row][row];
+    }
+    Some(x)
+}
+
+/// Eigenvalues and eigenvectors, as the columns of the returned matrix, of a symmetric matrix
+/// by Jacobi rotations.
+fn eigen(mut a: [[f64; 3]; 3]) -> ([f64; 3], [[f64; 3]; 3]) {
+    let mut v = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+    for _ in 0..50 {
+        let off = a[0][1].powi(2) + a[0][2].powi(2) + a[1][2].powi(2);
+        if off < 1e-24 {
+            break;
+        }
+        for (p, q) in [(0, 1), (0, 2), (1, 2)] {
+            if a[p][q].abs() < 1e-30 {
+                continue;
+            }
+            let theta = (a[q][q] - a[p][p]) / (2.0 * a[p][q]);
+            let t = theta.signum() / (theta.abs() + (theta * theta + 1.0).sqrt());
+            let c = 1.0 / (t * t + 1.0).sqrt();
+            let s = t * c;
+            for row in &mut a {
+                let (ap, aq) = (row[p], row[q]);
+                row[p] = c * ap - s * aq;
+                row[q] = s * ap + c * aq;
+            }
+            let (row_p, row_q) = (a[p], a[q]);
+            a[p] = [0, 1, 2].map(|k| c * row_p[k] - s * row_q[k]);
+            a[q] = [0, 1, 2].map(|k| s * row_p[k] + c * row_q[k]);
+            for row in &mut v {
+                let (vp, vq) = (row[p], row[q]);
+                row[p] = c * vp - s * vq;
+                row[q] = s * vp + c * vq;
+            }
+        }
+    }
+    ([a[0][0], a[1][1], a[2][2]], v)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Points spread evenly over the unit sphere
+    fn sphere(n: usize) -> Vec<[f64; 3]> {
+        let golden = std::f64::consts::PI * (3.0 - 5f64.sqrt());
+        (0..n)
+            .map(|i| {
+                let z = 1.0 - 2.0 * (i as f64 + 0.5) / n as f64;
+                let r = (1.0 - z * z).sqrt();
+                let (s, c) = (golden * i as f64).sin_cos();
+                [r * c, r * s, z]
+            })
+            .collect()
+    }
+
+    /// Rotation by `x`, `y` and `z` radians about the respective axes
+    fn rotation(x: f64, y: f64, z: f64) -> [[f64; 3]; 3] {
+        let (sx, cx) = x.sin_cos();
+        let (sy, cy) = y.sin_cos();
+        let (sz, cz) = z.sin_cos();
+        let rx = [[1.0, 0.0, 0.0], [0.0, cx, -sx], [0.0, sx, cx]];
+        let ry = [[cy, 0.0, sy], [0.0, 1.0, 0.0], [-sy, 0.0, cy]];
+        let rz = [[cz, -sz, 0.0], [sz, cz, 0.0], [0.0, 0.0, 1.0]];
+        mul(&mul(&rz, &ry), &rx)
+    }
+
+    fn mul(a: &[[f64; 3]; 3], b: &[[f64; 3]; 3]) -> [[f64; 3]; 3] {
+        a.map(|row| [0, 1, 2].map(|j| (0..3).map(|k| row[k] * b[k][j]).sum()))
+    }
+
+    fn transpose(m: &[[f64; 3]; 3]) -> [[f64; 3]; 3] {
+        [0, 1, 2].map(|i| [0, 1, 2].map(|j| m[j][i]))
+    }
+
+    #[test]
+    fn recovers_offset_scaled_rotated_ellipsoid() {
+        let bias = [12.0, -30.0, 7.5];
+        let axes = [40.0, 50.0, 60.0];
+        let q = rotation(0.3, -0.5, 1.1);
+        let samples: Vec<[f32; 3]> = sphere(200)
+            .into_iter()
+            .map(|u| {
+                let v = mat_vec(&q, &[0, 1, 2].map(|i| u[i] * axes[i]));
+                [0, 1, 2].map(|i| (bias[i] + v[i]) as f32)
+            })
+            .collect();
+
+        let fit = fit(&samples).unwrap();
+        for (&b, expected) in fit.bias.iter().zip(bias) {
+            assert!((f64::from(b) - expected).abs() < 1e-2, "{:?}", fit.bias);
+        }
+
+        // Maps the ellipsoid onto a sphere with the geometric mean of its semi-axes as radius
+        let radius = (axes[0] * axes[1] * axes[2]).cbrt();
+        let scale = [0, 1, 2].map(|i| {
+            let mut row = [0.0; 3];
+            row[i] = radius / axes[i];
+            row
+        });
+        let expected = mul(&mul(&q, &scale), &transpose(&q));
+        for (row, expected) in fit.matrix.iter().zip(expected) {
+            for (&m, e) in row.iter().zip(expected) {
+                assert!((f64::from(m) - e).abs() < 1e-3, "{:?}", fit.matrix);
+            }
+        }
+
+        for sample in &samples {
+            let centered = [0, 1, 2].map(|i| sample[i] - fit.bias[i]);
+            let corrected = fit.matrix.map(|row| (0..3).map(|k| row[k] * centered[k]).sum());
+            let norm = corrected.iter().map(|x: &f32| x * x).sum::<f32>().sqrt();
+            assert!((f64::from(norm) - radius).abs() < 1e-2, "{norm}");
+        }
+    }
+
+    #[test]
+    fn planar_window_is_rejected() {
+        // Turning the mast only about its vertical axis leaves the samples on a plane
+        let q = rotation(0.2, 0.4, 0.0);
+        let samples: Vec<[f32; 3]> = (0..100)
+            .map(|i| {
+                let (s, c) = (f64::from(i) * 0.1).sin_cos();
+                let v = mat_vec(&q, &[40.0 * c, 40.0 * s, 0.0]);
+                [(v[0] + 5.0) as f32, (v[1] - 3.0) as f32, (v[2] + 20.0) as f32]
+            })
+            .collect();
+        assert!(fit(&samples).is_none());
+
+        let flat: Vec<[f32; 3]> = samples.iter().map(|&[x, y, _]| [x, y, 0.0]).collect();
+        assert!(fit(&flat).is_none());
+    }
+
+    #[test]
+    fn too_few_or_identical_samples_are_rejected() {
+        assert!(fit(&[[1.0, 2.0, 3.0]; 8]).is_none());
+        assert!(fit(&[[1.0, 2.0, 3.0]; 50]).is_none());
+    }
+}
@@ -0,0 +1,141 @@
+/// Madgwick's gradient descent AHRS filter, fusing gyroscope, accelerometer and magnetometer
+/// readings into an orientation quaternion.
+///
+/// See S. Madgwick, "An efficient orientation filter for inertial and inertial/magnetic sensor
+/// arrays", 2010.
+#[derive(Debug, Clone, Copy)]
+pub struct Madgwick {
+    beta: f32,
+    /// Orientation as `[w, x, y, z]`
+    quat: [f32; 4],
+}
+
+fn normalized<const N: usize>(v: [f32; N]) -> Option<[f32; N]> {
+    let norm = v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm == 0.0 || !norm.is_finite() {
+        return None;
+    }
+    Some(v.map(|x| x / norm))
+}
+
+impl Madgwick {
+    pub fn new(beta: f32) -> Self {
+        Self {
+            beta,
+            quat: [1.0, 0.0, 0.0, 0.0],
+        }
+    }
+
+    pub fn quaternion(&self) -> [f32; 4] {
+        self.quat
+    }
+
+    /// Orientation as `[roll, pitch, yaw]` in degrees, applied in yaw, pitch, roll order.
+    pub fn euler(&self) -> [f32; 3] {
+        let [w, x, y, z] = self.quat;
+        let roll = (2.0 * (w * x + y * z)).atan2(1.0 - 2.0 * (x * x + y * y));
+        let pitch = (2.0 * (w * y - z * x)).clamp(-1.0, 1.0).asin();
+        let yaw = (2.0 * (w * z + x * y)).atan2(1.0 - 2.0 * (y * y + z * z));
+        [roll, pitch, yaw].map(f32::to_degrees)
+    }
+
+    /// Advances the filter by `dt` seconds. `gyro` is in rad/s, `acc` and `mag` may be in any
+    /// unit as only their directions are used.
+    #[allow(clippy::many_single_char_names, clippy::similar_names)]
+    pub fn update(&mut self, gyro: [f32; 3], acc: [f32; 3], mag: [f32; 3], dt: f32) {
+        let [q0, q1, q2, q3] = self.quat;
+        let [gx, gy, gz] = gyro;
+
+        // Rate of change of the quaternion from the gyroscope
+        let mut q_dot = [
+            0.5 * (-q1 * gx - q2 * gy - q3 * gz),
+            0.5 * (q0 * gx + q2 * gz - q3 * gy),
+            0.5 * (q0 * gy - q1 * gz + q3 * gx),
+            0.5 * (q0 * gz + q1 * gy - q2 * gx),
+        ];
+
+        if let (Some([ax, ay, az]), Some([mx, my, mz])) = (normalized(acc), normalized(mag)) {
+            let _2q0mx = 2.0 * q0 * mx;
+            let _2q0my = 2.0 * q0 * my;
+            let _2q0mz = 2.0 * q0 * mz;
+            let _2q1mx = 2.0 * q1 * mx;
+            let _2q0 = 2.0 * q0;
+            let _2q1 = 2.0 * q1;
+            let _2q2 = 2.0 * q2;
+            let _2q3 = 2.0 * q3;
+            let _2q0q2 = 2.0 * q0 * q2;
+            let _2q2q3 = 2.0 * q2 * q3;
+            let q0q0 = q0 * q0;
+            let q0q1 = q0 * q1;
+            let q0q2 = q0 * q2;
+            let q0q3 = q0 * q3;
+            let q1q1 = q1 * q1;
+            let q1q2 = q1 * q2;
+            let q1q3 = q1 * q3;
+            let q2q2 = q2 * q2;
+            let q2q3 = q2 * q3;
+            let q3q3 = q3 * q3;
+
+            // Reference direction of Earth's magnetic field
+            let hx = mx * q0q0 - _2q0my * q3 + _2q0mz * q2 + mx * q1q1 + _2q1 * my * q2
+                + _2q1 * mz * q3
+                - mx * q2q2
+                - mx * q3q3;
+            let hy = _2q0mx * q3 + my * q0q0 - _2q0mz * q1 + _2q1mx * q2 - my * q1q1
+                + my * q2q2
+                + _2q2 * mz * q3
+                - my * q3q3;
+            let _2bx = (hx * hx + hy * hy).sqrt();
+            let _2bz = -_2q0mx * q2 + _2q0my * q1 + mz * q0q0 + _2q1mx * q3 - mz * q1q1
+                + _2q2 * my * q3
+                - mz * q2q2
+                + mz * q3q3;
+            let _4bx = 2.0 * _2bx;
+            let _4bz = 2.0 * _2bz;
+
+            // Objective function errors
+            let fax = 2.0 * q1q3 - _2q0q2 - ax;
+            let fay = 2.0 * q0q1 + _2q2q3 - ay;
+            let faz = 1.0 - 2.0 * q1q1 - 2.0 * q2q2 - az;
+            let fmx = _2bx * (0.5 - q2q2 - q3q3) + _2bz * (q1q3 - q0q2) - mx;
+            let fmy = _2bx * (q1q2 - q0q3) + _2bz * (q0q1 + q2q3) - my;
+            let fmz = _2bx * (q0q2 + q1q3) + _2bz * (0.5 - q1q1 - q2q2) - mz;
+
+            // Gradient descent step
+            let step = [
+                -_2q2 * fax + _2q1 * fay - _2bz * q2 * fmx
+                    + (-_2bx * q3 + _2bz * q1) * fmy
+                    + _2bx * q2 * fmz,
+                _2q3 * fax + _2q0 * fay - 4.0 * q1 * faz
+                    + _2bz * q3 * fmx
+                    + (_2bx * q2 + _2bz * q0) * fmy
+                    + (_2bx * q3 - _4bz * q1) * fmz,
+                -_2q0 * fax + _2q3 * fay - 4.0 * q2 * faz
+                    + (-_4bx * q2 - _2bz * q0) * fmx
+                    + (_2bx * q1 + _2bz * q3) * fmy
+                    + (_2bx * q0 - _4bz * q2) * fmz,
+                _2q1 * fax
+                    + _2q2 * fay
+                    + (-_4bx * q3 + _2bz * q1) * fmx
+                    + (-_2bx * q0 + _2bz * q2) * fmy
+                    + _2bx * q1 * fmz,
+            ];
+
+            if let Some(step) = normalized(step) {
+                for (q, s) in q_dot.iter_mut().zip(step) {
+                    *q -= self.beta * s;
+                }
+            }
+        }
+
+        let quat = [
+            q0 + q_dot[0] * dt,
+            q1 + q_dot[1] * dt,
+            q2 + q_dot[2] * dt,
+            q3 + q_dot[3] * dt,
+        ];
+        if let Some(quat) = normalized(quat) {
+            self.quat = quat;
+        }
+    }
+}
@@ -1,30 +1,83 @@
 use std::thread;
+use std::time::Instant;
 
+use chrono::{DateTime, Utc};
 use ina219::address::Address;
-use ina219::calibration::UnCalibrated;
+use ina219::calibration::{IntCalibration, MicroAmpere, UnCalibrated};
 use ina219::SyncIna219;
+#[cfg(not(feature = "mock-hardware"))]
+use rppal::i2c::I2c;
 use serde::{Deserialize, Serialize};
 
-use super::Device;
+use super::{out_of_range, Device};
+#[cfg(feature = "mock-hardware")]
+use crate::mock::I2c;
 
+/// Shunt parameters used to program the INA219 calibration register.
+#[derive(Debug, Clone, Copy)]
+pub struct Calibration {
+    /// Shunt resistance in micro-ohms
+    pub shunt_uohm: u32,
+    /// Largest current expected through the shunt in milliamperes
+    pub max_current_ma: u32,
+}
+
+enum InaDevice {
+    UnCalibrated(SyncIna219<I2c, UnCalibrated>),
+    Calibrated(SyncIna219<I2c, IntCalibration>),
+}
+
+/// The INA219 powers up in continuous mode, so its registers always hold the last completed
+/// conversion and can be read without waiting. Only the first conversion after initialization
+/// has to be waited for.
 pub struct Ina {
-    device: SyncIna219<rppal::i2c::I2c, UnCalibrated>,
+    device: InaDevice,
+    /// When the first conversion after initialization completes
+    ready_at: Instant,
 }
 
 impl Ina {
-    pub fn new() -> Result<Self, Error> {
-        let i2c = rppal::i2c::I2c::new()?;
-        let ina = SyncIna219::new(i2c, Address::from_byte(0x40)?)?;
-        Ok(Self { device: ina })
+    const ADDRESS: u8 = 0x40;
+
+    /// Without a `calibration` the device runs uncalibrated and only the voltages are in real
+    /// units.
+    pub fn new(calibration: Option<Calibration>) -> Result<Self, Error> {
+        let i2c = I2c::new()?;
+        let address = Address::from_byte(Self::ADDRESS)?;
+        let mut device = if let Some(calibration) = calibration {
+            // The current register holds a signed 15 bit value, round the LSB up so the maximum
+            // current still fits
+            let current_lsb = (i64::from(calibration.max_current_ma) * 1000 + (1 << 15) - 1) >> 15;
+            let Some(calibration) =
+                IntCalibration::new(MicroAmpere(current_lsb), calibration.shunt_uohm)
+            else {
+                return Err(Error::InvalidCalibration);
+            };
+            InaDevice::Calibrated(SyncIna219::new_calibrated(i2c, address, calibration)?)
+        } else {
+            InaDevice::UnCalibrated(SyncIna219::new(i2c, address)?)
+        };
+        let configuration = match &mut device {
+            InaDevice::UnCalibrated(device) => device.configuration()?,
+            InaDevice::Calibrated(device) => device.configuration()?,
+        };
+        let ready_at = Instant::now() + configuration.conversion_time().unwrap_or_default();
+        Ok(Self { device, ready_at })
     }
 }
 
+/// `current` is in mA and `power` in mW when `calibrated` is set, otherwise they are scaled
+/// raw register values.
 #[derive(Debug, Serialize, Deserialize, Default, Clone, Copy)]
 pub struct Data {
-    bus_voltage: u16,
+    /// mV
+    pub bus_voltage: u16,
+    /// uV
     shunt_voltage: i32,
-    current: u16,
+    current: f32,
     power: f32,
+    calibrated: bool,
+    timestamp: DateTime<Utc>,
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -34,7 +87,7 @@ pub enum Error {
     #[error("INA address error")]
     InaAddress(#[from] ina219::address::OutOfRange),
     #[error("INA init error")]
-    InaInit(#[from] ina219::errors::InitializationError<rppal::i2c::I2c, rppal::i2c::Error>),
+    InaInit(#[from] ina219::errors::InitializationError<I2c, rppal::i2c::Error>),
     #[error("INA config read error")]
     InaConfigRead(#[from] ina219::errors::ConfigurationReadError<rppal::i2c::Error>),
     #[error("INA measurement error")]
@@ -43,42 +96,69 @@ pub enum Error {
     InaBusRead(#[from] ina219::errors::BusVoltageReadError<rppal::i2c::Error>),
     #[error("INA shunt voltage read error")]
     InaShuntRead(#[from] ina219::errors::ShuntVoltageReadError<rppal::i2c::Error>),
+    #[error("INA calibration out of range")]
+    InvalidCalibration,
+    #[error("INA measurement not ready")]
+    NotReady,
 }
 
 impl Device for Ina {
     type Data = Data;
     type Error = Error;
+    type Settings = Option<Calibration>;
+
+    fn init(settings: &Self::Settings) -> Result<Self, Self::Error> {
+        Self::new(*settings)
+    }
+
+    fn implausible(data: &Self::Data) -> Option<String> {
+        // Full scale of the chip: 32 V bus and ±320 mV across the shunt
+        out_of_range("bus voltage", data.bus_voltage, 0..=32_000)
+            .or_else(|| out_of_range("shunt voltage", data.shunt_voltage, -320_000..=320_000))
+    }
+
+    fn not_ready(err: &Self::Error) -> bool {
+        matches!(err, Error::NotReady)
+    }
 
     fn get_data(&mut self) -> Result<Self::Data, Self::Error> {
-        if let Some(time) = self.device.configuration()?.conversion_time() {
-            thread::sleep(time);
+        let wait = self.ready_at.saturating_duration_since(Instant::now());
+        if !wait.is_zero() {
+            thread::sleep(wait);
         }
 
-        //let measure: Option<ina219::measurements::Measurements<(), ()>> = self.device.next_measurement()?;
-        //if let Some(measure) = self.device.next_measurement()? {
-        //    let power = measure.power;
-        //}
-        let bus_voltage = (self.device.bus_voltage()?).voltage_mv();
-        let shunt_voltage = (self.device.shunt_voltage()?).shunt_voltage_uv();
-        let current = (self.device.current_raw()?).0 * 10;
-        //let power = (self.device.power_raw()?).0 * 2;
-        #[allow(clippy::cast_precision_loss)]
-        let power = shunt_voltage.unsigned_abs() as f32 / 100.0;
-
-        //let d = Self::Data {
-        //    bus_voltage,
-        //    shunt_voltage,
-        //    current,
-        //    power,
-        //};
-
-        //eprintln!("{d:?}");
-
-        Ok(Self::Data {
-            bus_voltage,
-            shunt_voltage,
-            current,
-            power,
-        })
+        let timestamp = Utc::now();
+        match &mut self.device {
+            InaDevice::UnCalibrated(device) => {
+                let bus_voltage = (device.bus_voltage()?).voltage_mv();
+                let shunt_voltage = (device.shunt_voltage()?).shunt_voltage_uv();
+                let current = f32::from((device.current_raw()?).0) * 10.0;
+                #[allow(clippy::cast_precision_loss)]
+                let power = shunt_voltage.unsigned_abs() as f32 / 100.0;
+
+                Ok(Self::Data {
+                    bus_voltage,
+                    shunt_voltage,
+                    current,
+                    power,
+                    calibrated: false,
+                    timestamp,
+                })
+            }
+            InaDevice::Calibrated(device) => {
+                let Some(measurement) = device.next_measurement()? else {
+                    return Err(Error::NotReady);
+                };
+                #[allow(clippy::cast_precision_loss)]
+                Ok(Self::Data {
+                    bus_voltage: measurement.bus_voltage.voltage_mv(),
+                    shunt_voltage: measurement.shunt_voltage.shunt_voltage_uv(),
+                    current: measurement.current.0 as f32 / 1000.0,
+                    power: measurement.power.0 as f32 / 1000.0,
+                    calibrated: true,
+                    timestamp,
+                })
+            }
+        }
     }
 }
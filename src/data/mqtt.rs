@@ -0,0 +1,90 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, Scope};
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use rumqttc::{Client, ClientError, Event, MqttOptions, Packet, QoS, RecvTimeoutError};
+
+#[derive(Debug, Clone)]
+pub struct MqttSettings {
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    /// User name and password
+    pub credentials: Option<(String, String)>,
+    /// Topic every cycle is published to
+    pub telemetry_topic: String,
+    /// Number of messages waiting to be sent before new ones are dropped
+    pub queue: usize,
+}
+
+/// Publishes messages to an MQTT broker. The connection is driven by a dedicated thread, so
+/// an unreachable broker never stalls the reader loop.
+///
+/// After a connection error the broker is left alone for a growing interval before
+/// reconnecting; messages published meanwhile are dropped once the queue fills up.
+pub struct MqttPublisher {
+    client: Client,
+    topic: String,
+}
+
+impl MqttPublisher {
+    const MIN_BACKOFF: Duration = Duration::from_secs(5);
+    const MAX_BACKOFF: Duration = Duration::from_secs(300);
+    const KEEP_ALIVE: Duration = Duration::from_secs(30);
+
+    pub fn spawn<'a>(s: &'a Scope<'a, '_>, running: &'a AtomicBool, settings: MqttSettings) -> Self {
+        let mut options = MqttOptions::new(settings.client_id, settings.host, settings.port);
+        options.set_keep_alive(Self::KEEP_ALIVE);
+        if let Some((user, password)) = settings.credentials {
+            options.set_credentials(user, password);
+        }
+        let (client, mut connection) = Client::new(options, settings.queue.max(1));
+        thread::Builder::new()
+            .name("mqtt".to_owned())
+            .spawn_scoped(s, {
+                let client = client.clone();
+                move || {
+                    let mut backoff = Self::MIN_BACKOFF;
+                    let mut down_until: Option<Instant> = None;
+                    while running.load(Ordering::Relaxed) {
+                        if down_until.is_some_and(|t| Instant::now() < t) {
+                            thread::sleep(Duration::from_millis(500));
+                            continue;
+                        }
+                        match connection.recv_timeout(Duration::from_millis(500)) {
+                            Ok(Ok(Event::Incoming(Packet::ConnAck(_)))) => {
+                                info!("Connected to MQTT broker");
+                                down_until = None;
+                                backoff = Self::MIN_BACKOFF;
+                            }
+                            Ok(Ok(_)) | Err(RecvTimeoutError::Timeout) => {}
+                            Ok(Err(err)) => {
+                                warn!(
+                                    "MQTT connection error: {err}, reconnecting in {} s",
+                                    backoff.as_secs()
+                                );
+                                down_until = Some(Instant::now() + backoff);
+                                backoff = (backoff * 2).min(Self::MAX_BACKOFF);
+                            }
+                            Err(RecvTimeoutError::Disconnected) => break,
+                        }
+                    }
+                    let _ = client.disconnect();
+                }
+            })
+            .unwrap();
+        Self {
+            client,
+            topic: settings.telemetry_topic,
+        }
+    }
+
+    pub fn publish(&self, msg: String) {
+        match self.client.try_publish(&self.topic, QoS::AtMostOnce, false, msg) {
+            Ok(()) => {}
+            Err(ClientError::TryRequest(_)) => warn!("MQTT queue full, dropping message"),
+            Err(err) => warn!("Failed to publish to MQTT: {err}"),
+        }
+    }
+}
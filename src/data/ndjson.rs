@@ -0,0 +1,109 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use log::{error, info};
+
+use super::Snapshot;
+
+#[derive(Debug, Clone, Copy)]
+pub struct NdjsonSettings {
+    /// Size after which a new file is started
+    pub max_bytes: u64,
+    /// Age after which a new file is started
+    pub max_age: Duration,
+}
+
+impl Default for NdjsonSettings {
+    fn default() -> Self {
+        Self {
+            max_bytes: 16 * 1024 * 1024,
+            max_age: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+struct ActiveFile {
+    writer: BufWriter<File>,
+    opened: Instant,
+    bytes: u64,
+}
+
+/// Appends every cycle as one line to a rolling `{nanos}.ndjson` file, named after the cycle
+/// that started it, instead of a file per cycle. `current.ndjson` links to the active file.
+pub struct NdjsonLog {
+    dir: PathBuf,
+    link: PathBuf,
+    settings: NdjsonSettings,
+    active: Option<ActiveFile>,
+}
+
+impl NdjsonLog {
+    pub fn new(dir: &Path, settings: NdjsonSettings) -> Self {
+        Self {
+            dir: dir.to_owned(),
+            link: dir.join("current.ndjson"),
+            settings,
+            active: None,
+        }
+    }
+
+    fn open(&self, nanos: i64) -> std::io::Result<ActiveFile> {
+        let path = self.dir.join(format!("{nanos}.ndjson"));
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        if self.link.symlink_metadata().is_ok() {
+            std::fs::remove_file(&self.link)?;
+        }
+        std::os::unix::fs::symlink(&path, &self.link)?;
+        info!("Writing data to {}", path.display());
+        Ok(ActiveFile {
+            writer: BufWriter::new(file),
+            opened: Instant::now(),
+            bytes: 0,
+        })
+    }
+
+    /// Appends `snapshot` as a line, returning whether it was fully written.
+    pub fn append(&mut self, snapshot: &Snapshot, nanos: i64) -> bool {
+        let mut line = match serde_json::to_vec(snapshot) {
+            Ok(line) => line,
+            Err(err) => {
+                error!("Failed to serialize data to json: {err}");
+                return false;
+            }
+        };
+        line.push(b'\n');
+
+        let expired = self.active.as_ref().is_some_and(|active| {
+            active.bytes >= self.settings.max_bytes
+                || active.opened.elapsed() >= self.settings.max_age
+        });
+        if expired {
+            self.active = None;
+        }
+        if self.active.is_none() {
+            match self.open(nanos) {
+                Ok(active) => self.active = Some(active),
+                Err(err) => {
+                    error!("Failed to open ndjson data file: {err}");
+                    return false;
+                }
+            }
+        }
+
+        let active = self.active.as_mut().unwrap();
+        match active.writer.write_all(&line).and_then(|()| active.writer.flush()) {
+            Ok(()) => {
+                active.bytes += line.len() as u64;
+                true
+            }
+            Err(err) => {
+                error!("Failed to append to ndjson data file: {err}");
+                // Start over in a new file, the current one may end in a partial line
+                self.active = None;
+                false
+            }
+        }
+    }
+}
@@ -0,0 +1,168 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, Scope};
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{Receiver, Sender, TrySendError};
+use log::{info, warn};
+use reqwest::blocking::{Client, Response};
+
+use super::spool::Spool;
+
+/// Posts messages to the publish endpoint from a dedicated thread, so a slow or unreachable
+/// endpoint never stalls the reader loop.
+///
+/// Messages are queued in a bounded channel; when it is full the oldest message is dropped.
+/// After a failed POST, or one answered with an error status, the endpoint is considered down
+/// and left alone for a growing interval, so an unreachable host isn't hit with a timeout on
+/// every message. With a `spool` the failed message and every message published while the
+/// endpoint is down go to disk instead, and are sent oldest first before any new ones once it's
+/// reachable again.
+pub struct Publisher {
+    tx: Sender<String>,
+    rx: Receiver<String>,
+}
+
+impl Publisher {
+    const MIN_BACKOFF: Duration = Duration::from_secs(5);
+    const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+    pub fn spawn<'a>(
+        s: &'a Scope<'a, '_>,
+        running: &'a AtomicBool,
+        client: Client,
+        url: String,
+        capacity: usize,
+        mut spool: Option<Spool>,
+    ) -> Self {
+        let (tx, rx) = crossbeam_channel::bounded::<String>(capacity.max(1));
+        thread::Builder::new()
+            .name("publish".to_owned())
+            .spawn_scoped(s, {
+                let rx = rx.clone();
+                move || {
+                    let mut down_until: Option<Instant> = None;
+                    let mut backoff = Self::MIN_BACKOFF;
+                    while running.load(Ordering::Relaxed) {
+                        if let Some(spool) = spool.as_mut() {
+                            // New messages line up behind the spooled ones to keep their order
+                            if down_until.is_some() || !spool.is_empty() {
+                                while let Ok(msg) = rx.try_recv() {
+                                    if let Err(err) = spool.push(&msg) {
+                                        warn!("Failed to spool message: {err}");
+                                    }
+                                }
+                            }
+                        }
+                        if down_until.is_some_and(|t| Instant::now() < t) {
+                            // Messages stay queued, the oldest get dropped if it fills up
+                            thread::sleep(Duration::from_millis(500));
+                            continue;
+                        }
+                        let spooled = spool.as_ref().and_then(|spool| {
+                            spool.peek().unwrap_or_else(|err| {
+                                warn!("Failed to read spooled message: {err}");
+                                None
+                            })
+                        });
+                        let (msg, from_spool) = match spooled {
+                            Some(msg) => (msg, true),
+                            None => match rx.recv_timeout(Duration::from_millis(500)) {
+                                Ok(msg) => (msg, false),
+                                Err(_) => continue,
+                            },
+                        };
+                        let sent = client
+                            .post(&url)
+                            .body(msg.clone())
+                            .send()
+                            .and_then(Response::error_for_status);
+                        match sent {
+                            Ok(_) => {
+                                if down_until.take().is_some() {
+                                    info!("Publish endpoint reachable again");
+                                }
+                                backoff = Self::MIN_BACKOFF;
+                                if let Some(spool) = spool.as_mut().filter(|_| from_spool) {
+                                    if let Err(err) = spool.pop(&msg) {
+                                        warn!("Failed to remove sent message from spool: {err}");
+                                    }
+                                }
+                            }
+                            Err(err) => {
+                                warn!(
+                                    "Failed to make POST request: {err}, retrying in {} s",
+                                    backoff.as_secs()
+                                );
+                                down_until = Some(Instant::now() + backoff);
+                                backoff = (backoff * 2).min(Self::MAX_BACKOFF);
+                                if let Some(spool) = spool.as_mut().filter(|_| !from_spool) {
+                                    if let Err(err) = spool.push(&msg) {
+                                        warn!("Failed to spool message: {err}");
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            })
+            .unwrap();
+        Self { tx, rx }
+    }
+
+    pub fn publish(&self, msg: String) {
+        let mut msg = msg;
+        loop {
+            match self.tx.try_send(msg) {
+                Ok(()) | Err(TrySendError::Disconnected(_)) => return,
+                Err(TrySendError::Full(m)) => {
+                    if self.rx.try_recv().is_ok() {
+                        warn!("Publish queue full, dropping oldest message");
+                    }
+                    msg = m;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::atomic::AtomicUsize;
+
+    #[test]
+    fn error_status_keeps_message_spooled() {
+        let dir = tempfile::tempdir().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let running = AtomicBool::new(true);
+        let requests = AtomicUsize::new(0);
+        thread::scope(|s| {
+            s.spawn(|| {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut buf = [0; 4096];
+                let _ = stream.read(&mut buf);
+                requests.fetch_add(1, Ordering::Relaxed);
+                stream
+                    .write_all(b"HTTP/1.1 503 Service Unavailable\r\ncontent-length: 0\r\n\r\n")
+                    .unwrap();
+            });
+            let spool = Spool::open(dir.path(), 1 << 20).unwrap();
+            let publisher = Publisher::spawn(s, &running, Client::new(), url, 4, Some(spool));
+            publisher.publish("reading".to_owned());
+            let start = Instant::now();
+            while requests.load(Ordering::Relaxed) == 0 && start.elapsed() < Duration::from_secs(5)
+            {
+                thread::sleep(Duration::from_millis(10));
+            }
+            // let the publisher handle the response before stopping it
+            thread::sleep(Duration::from_millis(200));
+            running.store(false, Ordering::Relaxed);
+        });
+        assert_eq!(requests.load(Ordering::Relaxed), 1);
+        let spool = Spool::open(dir.path(), 1 << 20).unwrap();
+        assert_eq!(spool.peek().unwrap().as_deref(), Some("reading"));
+    }
+}
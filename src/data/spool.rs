@@ -0,0 +1,125 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use log::{info, warn};
+
+/// Messages that couldn't be published, kept on disk until the endpoint is reachable again.
+///
+/// Messages are appended as lines to `outbox.ndjson` and sent again oldest first.
+/// `outbox.offset` holds the position of the oldest unsent message, so messages sent before a
+/// restart aren't sent twice. Once more than `max_bytes` are waiting the oldest are dropped.
+pub struct Spool {
+    path: PathBuf,
+    offset_path: PathBuf,
+    max_bytes: u64,
+    /// Position of the oldest unsent message
+    head: u64,
+    /// Length of the file
+    len: u64,
+}
+
+impl Spool {
+    pub fn open(dir: &Path, max_bytes: u64) -> io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let path = dir.join("outbox.ndjson");
+        let offset_path = dir.join("outbox.offset");
+        let len = match std::fs::metadata(&path) {
+            Ok(metadata) => metadata.len(),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => 0,
+            Err(err) => return Err(err),
+        };
+        let head = std::fs::read_to_string(&offset_path)
+            .ok()
+            .and_then(|offset| offset.trim().parse().ok())
+            .unwrap_or(0)
+            .min(len);
+        if head < len {
+            info!("{} bytes of unpublished messages in {}", len - head, path.display());
+        }
+        Ok(Self {
+            path,
+            offset_path,
+            max_bytes,
+            head,
+            len,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.head >= self.len
+    }
+
+    pub fn push(&mut self, msg: &str) -> io::Result<()> {
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        file.write_all(msg.as_bytes())?;
+        file.write_all(b"\n")?;
+        self.len += msg.len() as u64 + 1;
+
+        if self.len - self.head > self.max_bytes {
+            let mut reader = self.reader()?;
+            let mut line = String::new();
+            let mut dropped = 0;
+            while self.len - self.head > self.max_bytes {
+                line.clear();
+                let read = reader.read_line(&mut line)?;
+                if read == 0 {
+                    break;
+                }
+                self.head += read as u64;
+                dropped += 1;
+            }
+            warn!("Publish spool full, dropped {dropped} oldest messages");
+            self.save_head()?;
+        }
+        // Sent messages are only removed from the file once it grows well past the limit
+        if self.len > 2 * self.max_bytes {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    /// Returns the oldest unsent message.
+    pub fn peek(&self) -> io::Result<Option<String>> {
+        if self.is_empty() {
+            return Ok(None);
+        }
+        let mut line = String::new();
+        self.reader()?.read_line(&mut line)?;
+        Ok(Some(line.trim_end_matches('\n').to_owned()))
+    }
+
+    /// Marks `msg`, which must be the oldest unsent message, as sent.
+    pub fn pop(&mut self, msg: &str) -> io::Result<()> {
+        self.head = (self.head + msg.len() as u64 + 1).min(self.len);
+        if self.is_empty() {
+            File::create(&self.path)?;
+            self.head = 0;
+            self.len = 0;
+        }
+        self.save_head()
+    }
+
+    fn reader(&self) -> io::Result<BufReader<File>> {
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(self.head))?;
+        Ok(BufReader::new(file))
+    }
+
+    fn save_head(&self) -> io::Result<()> {
+        std::fs::write(&self.offset_path, self.head.to_string())
+    }
+
+    /// Rewrites the file without the messages already sent or dropped.
+    fn compact(&mut self) -> io::Result<()> {
+        let tmp = self.path.with_extension("ndjson.tmp");
+        let copied = io::copy(&mut self.reader()?, &mut File::create(&tmp)?)?;
+        // Resetting the offset first means a crash in between resends messages rather than
+        // skipping them
+        self.head = 0;
+        self.save_head()?;
+        std::fs::rename(&tmp, &self.path)?;
+        self.len = copied;
+        Ok(())
+    }
+}
@@ -0,0 +1,62 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, Scope};
+use std::time::Duration;
+
+use log::{info, warn};
+use parking_lot::Mutex;
+use tiny_http::{Header, Method, Response, Server};
+
+use super::Snapshot;
+
+/// Serves the statuses of the latest read cycle as JSON on `GET /status`, a liveness check
+/// operators can poll without logging in.
+pub fn spawn<'a>(
+    s: &'a Scope<'a, '_>,
+    running: &'a AtomicBool,
+    port: u16,
+    snapshot: Arc<Mutex<Snapshot>>,
+) {
+    let server = match Server::http(("0.0.0.0", port)) {
+        Ok(server) => server,
+        Err(err) => {
+            warn!("Failed to start status server on port {port}: {err}");
+            return;
+        }
+    };
+    info!("Serving statuses on port {port}");
+    thread::Builder::new()
+        .name("status".to_owned())
+        .spawn_scoped(s, move || {
+            while running.load(Ordering::Relaxed) {
+                let request = match server.recv_timeout(Duration::from_millis(500)) {
+                    Ok(Some(request)) => request,
+                    Ok(None) => continue,
+                    Err(err) => {
+                        warn!("Status server error: {err}");
+                        continue;
+                    }
+                };
+                let result = if *request.method() == Method::Get && request.url() == "/status" {
+                    let statuses = snapshot.lock().statuses.clone();
+                    match serde_json::to_string(&statuses) {
+                        Ok(json) => {
+                            let header =
+                                Header::from_bytes("Content-Type", "application/json").unwrap();
+                            request.respond(Response::from_string(json).with_header(header))
+                        }
+                        Err(err) => {
+                            warn!("Failed to serialize statuses to json: {err}");
+                            request.respond(Response::empty(500))
+                        }
+                    }
+                } else {
+                    request.respond(Response::empty(404))
+                };
+                if let Err(err) = result {
+                    warn!("Failed to answer status request: {err}");
+                }
+            }
+        })
+        .unwrap();
+}
@@ -1,23 +1,99 @@
+use std::thread;
 use std::time::{Duration, Instant};
 
-use rppal::uart::{Parity, Uart};
+use chrono::{DateTime, Utc};
+use rppal::uart::Parity;
+#[cfg(not(feature = "mock-hardware"))]
+use rppal::uart::Uart;
 use serde::{Deserialize, Serialize};
 
-use super::Device;
+use super::device_manager::{UartDeviceSettings, WindSettings};
+use super::{out_of_range, Device};
+#[cfg(feature = "mock-hardware")]
+use crate::mock::Uart;
+
+/// Converts raw register values to m/s and degrees, firmware variants differ in the speed
+/// encoding and units.
+#[derive(Debug, Clone, Copy)]
+pub struct WindScaling {
+    /// Speed is a big-endian u16 in register 2 instead of a big-endian f32 in registers 2-3
+    pub integer_speed: bool,
+    pub speed_factor: f32,
+    pub speed_offset: f32,
+    pub dir_factor: f32,
+}
+
+impl Default for WindScaling {
+    fn default() -> Self {
+        Self {
+            integer_speed: false,
+            speed_factor: 1.0,
+            speed_offset: 0.0,
+            dir_factor: 1.0,
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct Wind {
     device: Uart,
+    scaling: WindScaling,
+    /// Modbus RTU inter-frame silence at the configured baud rate
+    silence: Duration,
 }
 
 impl Wind {
     const QUERY: [u8; 8] = [0x01, 0x03, 0x00, 0x00, 0x00, 0x26, 0xC4, 0x10];
+    /// Response to `QUERY`: address, function, byte count, 38 registers and CRC
+    const FRAME_LEN: usize = 81;
 
-    pub fn new(port: &str, baud_rate: u32, timeout: Duration) -> Result<Self, Error> {
+    pub fn new(
+        port: &str,
+        baud_rate: u32,
+        timeout: Duration,
+        scaling: WindScaling,
+    ) -> Result<Self, Error> {
         let mut uart = Uart::with_path(port, baud_rate, Parity::None, 8, 1)?;
         uart.set_read_mode(0, timeout)?;
         uart.set_write_mode(true)?;
-        Ok(Self { device: uart })
+        Ok(Self {
+            device: uart,
+            scaling,
+            silence: Self::frame_silence(baud_rate),
+        })
+    }
+
+    /// 3.5 character times of an 11 bit character, fixed at 1.75 ms above 19200 baud as the
+    /// Modbus RTU spec requires.
+    fn frame_silence(baud_rate: u32) -> Duration {
+        if baud_rate > 19_200 {
+            Duration::from_micros(1750)
+        } else {
+            Duration::from_secs_f64(3.5 * 11.0 / f64::from(baud_rate.max(1)))
+        }
+    }
+
+    /// Decodes a response frame to `QUERY`.
+    ///
+    /// The registers start after the 3 byte header, register 1 (bytes 5-6) holds the
+    /// direction as a big-endian u16 and registers 2-3 (bytes 7-10) the speed, either as a
+    /// big-endian f32 or, with `WindScaling::integer_speed`, as a big-endian u16 in register 2.
+    /// Both are then scaled by `scaling`.
+    pub fn parse_frame(frame: &[u8; Self::FRAME_LEN], scaling: &WindScaling) -> Data {
+        let dir = f32::from(u16::from_be_bytes([frame[5], frame[6]])) * scaling.dir_factor;
+        let speed = if scaling.integer_speed {
+            f32::from(u16::from_be_bytes([frame[7], frame[8]]))
+        } else {
+            f32::from_be_bytes([frame[7], frame[8], frame[9], frame[10]])
+        };
+        let speed = speed * scaling.speed_factor + scaling.speed_offset;
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        let dir = dir.round() as u16;
+        Data {
+            dir,
+            speed,
+            timestamp: Utc::now(),
+        }
     }
 
     //pub fn send_query(&mut self) -> Result<(), Error> {
@@ -29,8 +105,9 @@ impl Wind {
 
 #[derive(Debug, Serialize, Deserialize, Default, Clone, Copy)]
 pub struct Data {
-    dir: u16,
-    speed: f32,
+    pub dir: u16,
+    pub speed: f32,
+    timestamp: DateTime<Utc>,
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -44,28 +121,89 @@ pub enum Error {
 impl Device for Wind {
     type Data = Data;
     type Error = Error;
+    type Settings = WindSettings;
+
+    fn init(settings: &Self::Settings) -> Result<Self, Self::Error> {
+        let UartDeviceSettings { port, baud_rate, timeout } = &settings.uart;
+        Self::new(port, *baud_rate, *timeout, settings.scaling)
+    }
+
+    fn implausible(data: &Self::Data) -> Option<String> {
+        out_of_range("speed", data.speed, 0.0..=75.0)
+            .or_else(|| out_of_range("direction", data.dir, 0..=360))
+    }
 
     fn get_data(&mut self) -> Result<Self::Data, Self::Error> {
         const TIMEOUT: Duration = Duration::from_millis(800);
 
         self.device.flush(rppal::uart::Queue::Both)?;
+        // Frames are delimited by silence, so the query can't start right after earlier traffic
+        thread::sleep(self.silence);
         self.device.write(&Self::QUERY)?;
+
         let start = Instant::now();
-        let mut elapsed = start.elapsed();
-        while self.device.input_len()? < 81 && elapsed < TIMEOUT {
-            elapsed = start.elapsed();
-        }
-        if elapsed >= TIMEOUT {
-            return Err(Error::NoData);
+        let mut buf = [0u8; Self::FRAME_LEN];
+        let mut len = 0;
+        while len < Self::FRAME_LEN {
+            if start.elapsed() >= TIMEOUT {
+                return Err(Error::NoData);
+            }
+            len += self.device.read(&mut buf[len..])?;
         }
-        if self.device.input_len()? != 81 {
+
+        // Anything arriving before the silence ends belongs to the same frame, which is then
+        // longer than expected and not a response to the query
+        thread::sleep(self.silence);
+        if self.device.input_len()? > 0 {
+            self.device.flush(rppal::uart::Queue::Input)?;
             return Err(Error::NoData);
         }
-        let mut buf = [0u8; 81];
-        let _n_bytes = self.device.read(&mut buf)?;
-        let dir = u16::from_be_bytes(buf[5..7].try_into().unwrap());
-        let speed = f32::from_be_bytes(buf[7..11].try_into().unwrap());
+        Ok(Self::parse_frame(&buf, &self.scaling))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A response frame with `dir` in register 1 and `speed` in registers 2-3
+    fn frame(dir: u16, speed: [u8; 4]) -> [u8; Wind::FRAME_LEN] {
+        let mut frame = [0u8; Wind::FRAME_LEN];
+        frame[..3].copy_from_slice(&[0x01, 0x03, 76]);
+        frame[5..7].copy_from_slice(&dir.to_be_bytes());
+        frame[7..11].copy_from_slice(&speed);
+        frame
+    }
+
+    #[test]
+    fn default_scaling() {
+        let data = Wind::parse_frame(&frame(180, 3.5f32.to_be_bytes()), &WindScaling::default());
+        assert_eq!(data.dir, 180);
+        assert!((data.speed - 3.5).abs() < f32::EPSILON);
+    }
+
+    #[test]
+    fn decimeter_per_second_firmware() {
+        let scaling = WindScaling {
+            integer_speed: true,
+            speed_factor: 0.1,
+            ..WindScaling::default()
+        };
+        // 35 in register 2, register 3 holds unrelated data
+        let data = Wind::parse_frame(&frame(270, [0x00, 0x23, 0xAB, 0xCD]), &scaling);
+        assert_eq!(data.dir, 270);
+        assert!((data.speed - 3.5).abs() < 1e-6);
+    }
 
-        Ok(Data { dir, speed })
+    #[test]
+    fn offset_and_direction_factor() {
+        let scaling = WindScaling {
+            speed_offset: 0.2,
+            dir_factor: 0.1,
+            ..WindScaling::default()
+        };
+        let data = Wind::parse_frame(&frame(1234, 0.0f32.to_be_bytes()), &scaling);
+        assert_eq!(data.dir, 123);
+        assert!((data.speed - 0.2).abs() < 1e-6);
     }
 }
@@ -0,0 +1,601 @@
+mod audio;
+pub mod config;
+mod data;
+#[cfg(feature = "mock-hardware")]
+mod mock;
+pub mod selftest;
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU8, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::{self, Scope};
+use std::time::Duration;
+
+use alsa::pcm::Format;
+use log::{info, warn};
+use parking_lot::Mutex;
+#[cfg(not(feature = "mock-hardware"))]
+use rppal::gpio::Gpio;
+use rppal::gpio::InputPin;
+
+use self::audio::{CaptureDevice, FlacEncoder};
+use self::config::{
+    CaptureConfig, ConfigFile, DataConfig, EndpointConfig, GpsConfig, HealthConfig,
+    SchedulingConfig, SensorConfig,
+};
+use self::audio::CaptureDeviceError;
+use self::data::mqtt::MqttSettings;
+
+/// Default length of audio files, see `CaptureSettings::file_duration`
+const AUDIO_FILE_DURATION: Duration = Duration::from_secs(10);
+
+const I2S_DEVICES: &[&str] = &["hw:CARD=ANDROSi2s,DEV=1"];
+const I2S_CHANNELS: u32 = 4;
+const I2S_SAMPLERATE: u32 = 192_000;
+/// A second identical card gets a `_1` suffix, which happens when the first one is re-plugged
+const UMC_DEVICES: &[&str] = &["hw:CARD=U192k,DEV=0", "hw:CARD=U192k_1,DEV=0"];
+const UMC_CHANNELS: u32 = 2;
+const UMC_SAMPLERATE: u32 = 48_000;
+
+/// Crate version and the git commit the binary was built from
+pub fn build_info() -> String {
+    let features = if cfg!(feature = "mock-hardware") {
+        " [mock-hardware]"
+    } else {
+        ""
+    };
+    format!("andros {} ({}){features}", env!("CARGO_PKG_VERSION"), env!("GIT_HASH"))
+}
+
+/// Reads a whole file and trims surrounding whitespace, returning `None` if the file can't be
+/// read or is empty.
+fn read_trimmed(path: &Path) -> Option<String> {
+    let mut buf = String::new();
+    match File::open(path).and_then(|mut file| file.read_to_string(&mut buf)) {
+        Ok(_) => {
+            let trimmed = buf.trim();
+            if trimmed.is_empty() {
+                warn!("File {} is empty", path.display());
+                None
+            } else {
+                Some(trimmed.to_owned())
+            }
+        }
+        Err(err) => {
+            warn!("Failed to read {}: {err}", path.display());
+            None
+        }
+    }
+}
+
+/// Checks for a colon-separated mac address, e.g. `aa:bb:cc:dd:ee:ff`
+fn is_valid_mac(mac: &str) -> bool {
+    let octets: Vec<&str> = mac.split(':').collect();
+    octets.len() == 6
+        && octets
+            .iter()
+            .all(|octet| octet.len() == 2 && octet.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+fn handle_capture_device_error(err: &CaptureDeviceError, status: &AtomicU8) {
+    warn!("{err}");
+    status.store(2, Ordering::Relaxed);
+    thread::sleep(Duration::from_secs(1));
+}
+
+/// An audio device to record from, along with where its recordings go and the status it
+/// reports to
+struct AudioDevice<'a> {
+    /// Short name, used for the capture thread
+    name: &'static str,
+    /// ALSA names tried in order
+    devices: &'a [String],
+    channels: u32,
+    samplerate: u32,
+    format: Format,
+    output_dir: PathBuf,
+    status: &'a AtomicU8,
+    /// Peak absolute sample per channel since the last report
+    peaks: &'a [AtomicI32],
+    /// Set on every PPS pulse
+    pps: Arc<Mutex<(bool, i64)>>,
+    /// `Config::audio_file_template` with the device name and mac filled in
+    file_template: String,
+    /// Length of each audio file
+    file_duration: Duration,
+    /// Silence after which the device is reinitialized
+    watchdog: Duration,
+    /// Blocks discarded after opening the device
+    warmup_blocks: usize,
+    flac: bool,
+}
+
+/// Applies `scheduling` to the calling thread. Without `CAP_SYS_NICE` this only warns and the
+/// thread keeps the default scheduling.
+fn apply_scheduling(name: &str, scheduling: SchedulingConfig) {
+    if let Some(priority) = scheduling.fifo_priority {
+        let param = libc::sched_param { sched_priority: priority };
+        // SAFETY: `pthread_self` is always a valid handle for the calling thread
+        let ret =
+            unsafe { libc::pthread_setschedparam(libc::pthread_self(), libc::SCHED_FIFO, &param) };
+        if ret == 0 {
+            info!("{name} capture running at real-time priority {priority}");
+        } else {
+            let err = std::io::Error::from_raw_os_error(ret);
+            warn!("Failed to set real-time priority {priority} for {name} capture: {err}");
+        }
+    }
+    if let Some(cpu) = scheduling.cpu {
+        // SAFETY: `cpu_set_t` is a plain bit mask, valid when zeroed
+        let ret = unsafe {
+            let mut set: libc::cpu_set_t = std::mem::zeroed();
+            libc::CPU_SET(cpu, &mut set);
+            libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set)
+        };
+        if ret == 0 {
+            info!("{name} capture pinned to cpu {cpu}");
+        } else {
+            let err = std::io::Error::last_os_error();
+            warn!("Failed to pin {name} capture to cpu {cpu}: {err}");
+        }
+    }
+}
+
+/// Spawns a capture thread per device, each writing to its own output directory.
+fn spawn_capture_threads<'a>(
+    s: &'a Scope<'a, '_>,
+    devices: Vec<AudioDevice<'a>>,
+    running: &'a AtomicBool,
+    low_space: &'a AtomicBool,
+    scheduling: SchedulingConfig,
+    flac: Option<FlacEncoder>,
+) {
+    for device in devices {
+        let flac = flac.clone().filter(|_| device.flac);
+        thread::Builder::new()
+            .name(device.name.to_owned())
+            .spawn_scoped(s, move || {
+                apply_scheduling(device.name, scheduling);
+                let mut capture = CaptureDevice::new(
+                    device.devices,
+                    device.channels,
+                    device.samplerate,
+                    device.format,
+                    device.output_dir,
+                    running,
+                    device.status,
+                    device.pps,
+                );
+                capture.set_checkpoint(&CHECKPOINT);
+                capture.set_peaks(device.peaks);
+                capture.set_low_space(low_space);
+                capture.set_file_template(&device.file_template);
+                if let Some(encoder) = flac {
+                    capture.set_flac(encoder);
+                }
+                capture.set_watchdog(device.watchdog);
+                capture.set_warmup_blocks(device.warmup_blocks);
+                while running.load(Ordering::Relaxed) {
+                    match capture.read(device.file_duration) {
+                        Ok(()) => {}
+                        Err(err) => handle_capture_device_error(&err, device.status),
+                    };
+                }
+            })
+            .unwrap();
+    }
+}
+
+/// Incremented to make every capture thread finalize its current file
+static CHECKPOINT: AtomicUsize = AtomicUsize::new(0);
+
+/// Finalizes the current recordings and continues in new files, so the station can be safely
+/// powered off. Sensor data files are already flushed after every write.
+pub fn request_checkpoint() {
+    CHECKPOINT.fetch_add(1, Ordering::Relaxed);
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("GPIO error: {0}")]
+    Gpio(#[from] rppal::gpio::Error),
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum SetupError {
+    #[error("{0} is not writable: {1}")]
+    Unwritable(PathBuf, std::io::Error),
+    #[error("Invalid config file {0}: {1}")]
+    InvalidConfig(PathBuf, String),
+}
+
+/// Resolved directories, the configuration is loaded from `andros_dir` once logging runs, so
+/// problems with it end up in the log
+pub struct Setup {
+    pub andros_dir: PathBuf,
+    pub log_dir: PathBuf,
+}
+
+/// Creates and removes a probe file, since directories on a read-only mount still exist and
+/// list fine.
+fn check_writable(dir: &Path) -> std::io::Result<()> {
+    let probe = dir.join(".write_probe");
+    File::create(&probe)?.write_all(b"andros")?;
+    std::fs::remove_file(probe)
+}
+
+/// Resolves the andros directory under `home`, falling back to the current directory if it
+/// can't be created.
+pub fn setup(home: &Path) -> Result<Setup, SetupError> {
+    let andros_dir = home.join("andros");
+    let andros_dir = match std::fs::create_dir_all(&andros_dir) {
+        Ok(()) => andros_dir,
+        Err(err) => {
+            eprintln!("Failed to create andros directory: {err}\nWriting data and logs to current directory.");
+            PathBuf::from(".")
+        }
+    };
+
+    // A failing SD card often ends up mounted read-only, running on would silently lose all
+    // recordings
+    check_writable(&andros_dir).map_err(|err| SetupError::Unwritable(andros_dir.clone(), err))?;
+
+    let log_dir = andros_dir.join("log");
+    Ok(Setup {
+        andros_dir,
+        log_dir,
+    })
+}
+
+/// Hardware parameters of a capture device
+#[derive(Debug, Clone)]
+pub struct CaptureSettings {
+    /// ALSA names tried in order
+    pub devices: Vec<String>,
+    /// Also the number of peaks in the status report
+    pub channels: u32,
+    pub samplerate: u32,
+    pub format: Format,
+    /// Compress finished recordings to FLAC
+    pub flac: bool,
+    /// Length of each audio file
+    pub file_duration: Duration,
+    /// Silence after which the device is reinitialized
+    pub watchdog: Duration,
+    /// Blocks discarded after opening the device, while the ADC settles
+    pub warmup_blocks: usize,
+}
+
+impl CaptureSettings {
+    fn new(devices: &[&str], channels: u32, samplerate: u32) -> Self {
+        Self {
+            devices: devices.iter().map(|&name| name.to_owned()).collect(),
+            channels,
+            samplerate,
+            format: Format::s32(),
+            flac: false,
+            file_duration: AUDIO_FILE_DURATION,
+            watchdog: CaptureDevice::DEFAULT_WATCHDOG,
+            warmup_blocks: CaptureDevice::DEFAULT_WARMUP_BLOCKS,
+        }
+    }
+
+    /// Overrides the values set in `config`.
+    fn apply(&mut self, config: CaptureConfig) -> Result<(), String> {
+        if let Some(devices) = config.devices {
+            self.devices = devices;
+        }
+        if let Some(channels) = config.channels {
+            if channels == 0 {
+                return Err("channels must be at least 1".to_owned());
+            }
+            self.channels = channels;
+        }
+        if let Some(samplerate) = config.samplerate {
+            self.samplerate = samplerate;
+        }
+        if let Some(format) = config.format {
+            self.format = format.parse().map_err(|_| format!("unknown format {format:?}"))?;
+        }
+        if let Some(flac) = config.flac {
+            self.flac = flac;
+        }
+        if let Some(secs) = config.file_duration {
+            if secs == 0 {
+                return Err("file_duration must be at least 1 second".to_owned());
+            }
+            self.file_duration = Duration::from_secs(secs);
+        }
+        if let Some(secs) = config.watchdog {
+            if secs == 0 {
+                return Err("watchdog must be at least 1 second".to_owned());
+            }
+            self.watchdog = Duration::from_secs(secs);
+        }
+        if let Some(blocks) = config.warmup_blocks {
+            self.warmup_blocks = blocks;
+        }
+        Ok(())
+    }
+}
+
+/// Everything the pipeline needs to know about the station it runs on
+pub struct Config {
+    /// Root directory for recordings, sensor data and calibration files
+    pub andros_dir: PathBuf,
+    /// Ip and mac identifier of the station, data isn't published without them
+    pub identity: Option<(String, String)>,
+    /// Name of audio files without the extension, `{device}` is replaced with the capture
+    /// device name, `{mac}` with the mac identifier, `{nanos}` with the creation time in
+    /// nanoseconds and `{time}` with it as an ISO-8601 UTC timestamp in milliseconds, e.g.
+    /// `{device}_{mac}_{time}`
+    pub audio_file_template: String,
+    pub capture_scheduling: SchedulingConfig,
+    pub i2s: CaptureSettings,
+    pub umc: CaptureSettings,
+    pub sensors: SensorConfig,
+    pub endpoints: EndpointConfig,
+    pub gps: GpsConfig,
+    pub data: DataConfig,
+    pub health: HealthConfig,
+    /// Broker cycles are also published to, from the `[mqtt]` section
+    pub mqtt: Option<MqttSettings>,
+    /// Config file the hardware and endpoint settings were read from, `None` when the built-in
+    /// defaults are used
+    pub source: Option<PathBuf>,
+}
+
+impl Config {
+    /// Reads the station identity from the `ip` and `mac` files in `andros_dir` and the
+    /// hardware settings from its `config.toml`, if there is one.
+    pub fn load(andros_dir: &Path) -> Result<Self, SetupError> {
+        let ip = read_trimmed(&andros_dir.join("ip")).filter(|ip| {
+            let valid = ip.parse::<IpAddr>().is_ok();
+            if !valid {
+                warn!("Invalid ip in ip file: {ip:?}");
+            }
+            valid
+        });
+
+        // Only the last two octets of the mac are used to identify the device
+        let mac = read_trimmed(&andros_dir.join("mac")).and_then(|mac| {
+            if is_valid_mac(&mac) {
+                Some(mac[(mac.len() - 5)..].to_owned())
+            } else {
+                warn!("Invalid mac in mac file: {mac:?}");
+                None
+            }
+        });
+
+        let identity = if let Some(ip) = ip {
+            mac.map(|mac| (ip, mac))
+        } else {
+            None
+        };
+
+        let mut i2s = CaptureSettings::new(I2S_DEVICES, I2S_CHANNELS, I2S_SAMPLERATE);
+        let mut umc = CaptureSettings::new(UMC_DEVICES, UMC_CHANNELS, UMC_SAMPLERATE);
+        let path = andros_dir.join(ConfigFile::NAME);
+        let invalid = |err| SetupError::InvalidConfig(path.clone(), err);
+        let file = ConfigFile::load(&path).map_err(invalid)?;
+        let source = file.is_some().then(|| path.clone());
+        let file = file.unwrap_or_default();
+        i2s.apply(file.i2s).map_err(invalid)?;
+        umc.apply(file.umc).map_err(invalid)?;
+        let scheduling = file.scheduling;
+        if scheduling.fifo_priority.is_some_and(|priority| !(1..=99).contains(&priority)) {
+            return Err(invalid("fifo_priority must be between 1 and 99".to_owned()));
+        }
+        if let Some(cpu) = scheduling.cpu {
+            let cpus = thread::available_parallelism()
+                .map_or(libc::CPU_SETSIZE as usize, |cpus| cpus.get())
+                .min(libc::CPU_SETSIZE as usize);
+            if cpu >= cpus {
+                return Err(invalid(format!("cpu must be below the {cpus} available cpus")));
+            }
+        }
+        let audio_file_template = file.audio_file_template.unwrap_or_else(|| "{nanos}".to_owned());
+        config::validate_file_template(&audio_file_template).map_err(invalid)?;
+        let mac = identity.as_ref().map(|(_, mac)| mac.as_str());
+        let mqtt = file.mqtt.map(|mqtt| mqtt.settings(mac)).transpose().map_err(invalid)?;
+
+        Ok(Self {
+            andros_dir: andros_dir.to_owned(),
+            identity,
+            audio_file_template,
+            capture_scheduling: scheduling,
+            i2s,
+            umc,
+            sensors: file.sensors,
+            endpoints: file.endpoints,
+            gps: file.gps,
+            data: file.data,
+            health: file.health,
+            mqtt,
+            source,
+        })
+    }
+}
+
+/// Logs the capture devices ALSA knows about and warns about configured ones that are missing,
+/// card names change when USB devices enumerate in a different order.
+#[cfg(not(feature = "mock-hardware"))]
+fn check_capture_devices(devices: &[AudioDevice]) {
+    let names = match audio::capture_device_names() {
+        Ok(names) => names,
+        Err(err) => {
+            warn!("Failed to list ALSA capture devices: {err}");
+            return;
+        }
+    };
+    info!("ALSA capture devices: {}", names.join(", "));
+    for device in devices {
+        if !names.iter().any(|name| device.devices.contains(name)) {
+            warn!(
+                "{} capture device {} not found, available: {}",
+                device.name,
+                device.devices.join(" or "),
+                names.join(", ")
+            );
+        }
+    }
+}
+
+/// Timestamps every rising edge of the GPS PPS output into `pps_slots`. The interrupt stays
+/// active as long as the returned pin is alive.
+#[cfg(not(feature = "mock-hardware"))]
+fn watch_pps(pps_slots: Vec<Arc<Mutex<(bool, i64)>>>) -> Result<Option<InputPin>, Error> {
+    let gpio = Gpio::new()?;
+    let mut pps_pin = gpio.get(13)?.into_input_pulldown();
+    pps_pin.set_async_interrupt(
+        rppal::gpio::Trigger::RisingEdge,
+        Some(Duration::from_millis(5)),
+        move |_| {
+            let now = chrono::Utc::now();
+            info!("PPS at UTC {now}");
+            let nanos = now.timestamp_nanos_opt().unwrap();
+            for pps in &pps_slots {
+                *pps.lock() = (true, nanos);
+            }
+        },
+    )?;
+    Ok(Some(pps_pin))
+}
+
+#[cfg(feature = "mock-hardware")]
+fn watch_pps(_pps_slots: Vec<Arc<Mutex<(bool, i64)>>>) -> Result<Option<InputPin>, Error> {
+    Ok(None)
+}
+
+/// Runs audio capture and sensor reading until `running` is cleared.
+pub fn run(config: Config, running: &AtomicBool) -> Result<(), Error> {
+    match &config.source {
+        Some(path) => info!("Hardware and endpoint configuration read from {}", path.display()),
+        None => info!("No {}, using the built-in configuration", ConfigFile::NAME),
+    }
+    let data_dir = &config.andros_dir.join("data");
+
+    let i2s_status = &AtomicU8::new(0);
+    let umc_status = &AtomicU8::new(0);
+    let low_space = &AtomicBool::new(false);
+    let peaks = |channels: u32| -> Vec<AtomicI32> {
+        std::iter::repeat_with(AtomicI32::default).take(channels as usize).collect()
+    };
+    let i2s_peaks = &peaks(config.i2s.channels)[..];
+    let umc_peaks = &peaks(config.umc.channels)[..];
+
+    let mac = config.identity.as_ref().map_or("", |(_, mac)| mac.as_str());
+    let file_template = |device: &str| {
+        config
+            .audio_file_template
+            .replace("{device}", device)
+            .replace("{mac}", &mac.replace(':', "-"))
+    };
+    let audio_devices = vec![
+        AudioDevice {
+            name: "i2s",
+            devices: &config.i2s.devices,
+            channels: config.i2s.channels,
+            samplerate: config.i2s.samplerate,
+            format: config.i2s.format,
+            output_dir: data_dir.join("i2s"),
+            status: i2s_status,
+            peaks: i2s_peaks,
+            pps: Arc::default(),
+            file_template: file_template("i2s"),
+            file_duration: config.i2s.file_duration,
+            watchdog: config.i2s.watchdog,
+            warmup_blocks: config.i2s.warmup_blocks,
+            flac: config.i2s.flac,
+        },
+        AudioDevice {
+            name: "umc",
+            devices: &config.umc.devices,
+            channels: config.umc.channels,
+            samplerate: config.umc.samplerate,
+            format: config.umc.format,
+            output_dir: data_dir.join("umc"),
+            status: umc_status,
+            peaks: umc_peaks,
+            pps: Arc::default(),
+            file_template: file_template("umc"),
+            file_duration: config.umc.file_duration,
+            watchdog: config.umc.watchdog,
+            warmup_blocks: config.umc.warmup_blocks,
+            flac: config.umc.flac,
+        },
+    ];
+
+    let dirs = audio_devices.iter().map(|d| (d.name, d.output_dir.clone()));
+    for (dir, path) in dirs.chain([("data", data_dir.join("data"))]) {
+        if !path.exists() {
+            std::fs::create_dir_all(path)
+                .unwrap_or_else(|e| warn!("Failed to create {dir} data directory: {e}"));
+        }
+    }
+
+    #[cfg(not(feature = "mock-hardware"))]
+    check_capture_devices(&audio_devices);
+
+    let pps_slots = audio_devices.iter().map(|d| d.pps.clone()).collect();
+    let _pps_pin = watch_pps(pps_slots)?;
+
+    thread::scope(|s| {
+        let flac = (config.i2s.flac || config.umc.flac).then(|| FlacEncoder::spawn(s)).flatten();
+        let scheduling = config.capture_scheduling;
+        spawn_capture_threads(s, audio_devices, running, low_space, scheduling, flac);
+
+        let mut reader = data::Reader::new(
+            data_dir.join("data"),
+            data_dir,
+            (i2s_status, i2s_peaks),
+            (umc_status, umc_peaks),
+        );
+        reader.set_low_space(low_space);
+        config.sensors.apply(&mut reader.device_manager.settings);
+        config.endpoints.apply(&mut reader.device_manager.settings);
+        config.gps.apply(&mut reader.device_manager.settings);
+        config.data.apply(&mut reader.device_manager.settings);
+        config.health.apply(&mut reader.device_manager.settings);
+        reader.device_manager.settings.mqtt = config.mqtt;
+        reader.read(running, s, config.identity);
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(config: &str) -> Result<Config, SetupError> {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join(ConfigFile::NAME), config).unwrap();
+        Config::load(dir.path())
+    }
+
+    #[test]
+    fn file_duration_per_device() {
+        let config = load("[i2s]\nfile_duration = 5").unwrap();
+        assert_eq!(config.i2s.file_duration, Duration::from_secs(5));
+        assert_eq!(config.umc.file_duration, AUDIO_FILE_DURATION);
+        assert!(load("[umc]\nfile_duration = 0").is_err());
+    }
+
+    #[test]
+    fn channels() {
+        assert_eq!(load("[umc]\nchannels = 6").unwrap().umc.channels, 6);
+        assert!(load("[i2s]\nchannels = 0").is_err());
+    }
+
+    #[test]
+    fn scheduling() {
+        let config = load("[scheduling]\nfifo_priority = 50\ncpu = 0").unwrap();
+        assert_eq!(config.capture_scheduling.fifo_priority, Some(50));
+        assert_eq!(config.capture_scheduling.cpu, Some(0));
+        assert!(load("[scheduling]\nfifo_priority = 100").is_err());
+        assert!(load("[scheduling]\ncpu = 1024").is_err());
+    }
+}
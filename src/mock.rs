@@ -0,0 +1,400 @@
+//! In-process stand-ins for the station hardware, so the pipeline builds and runs on a machine
+//! without the peripherals. The UART devices, audio capture and the AHT10, BMP280 and INA219 on
+//! the I2C bus are faked, the IMU simply fails to initialize and is reported as disconnected.
+
+use std::cell::Cell;
+use std::marker::PhantomData;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use alsa::pcm::Format;
+use embedded_hal::i2c::{ErrorType, Operation};
+use parking_lot::Mutex;
+use rppal::uart::{Parity, Queue};
+
+/// Replays a fixed GGA sentence on reads and answers Modbus register queries with a fixed wind
+/// frame.
+#[derive(Debug)]
+pub struct Uart {
+    pending: Mutex<Vec<u8>>,
+    timeout: Duration,
+}
+
+impl Uart {
+    const GGA: &'static [u8] =
+        b"$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*47\r\n";
+
+    pub fn with_path<P: AsRef<Path>>(
+        _path: P,
+        _baud_rate: u32,
+        _parity: Parity,
+        _data_bits: u8,
+        _stop_bits: u8,
+    ) -> rppal::uart::Result<Self> {
+        Ok(Self {
+            pending: Mutex::new(Vec::new()),
+            timeout: Duration::ZERO,
+        })
+    }
+
+    pub fn set_read_mode(&mut self, _min_length: u8, timeout: Duration) -> rppal::uart::Result<()> {
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    pub fn set_write_mode(&mut self, _blocking: bool) -> rppal::uart::Result<()> {
+        Ok(())
+    }
+
+    pub fn input_len(&self) -> rppal::uart::Result<usize> {
+        Ok(self.pending.lock().len())
+    }
+
+    pub fn read(&mut self, buffer: &mut [u8]) -> rppal::uart::Result<usize> {
+        let mut pending = self.pending.lock();
+        if pending.is_empty() {
+            thread::sleep(self.timeout);
+            pending.extend_from_slice(Self::GGA);
+        }
+        let n = pending.len().min(buffer.len());
+        buffer[..n].copy_from_slice(&pending[..n]);
+        pending.drain(..n);
+        Ok(n)
+    }
+
+    pub fn write(&mut self, buffer: &[u8]) -> rppal::uart::Result<usize> {
+        // Read holding registers, answered with 38 registers: direction 180 degrees in
+        // register 1 and 3.5 m/s as an f32 in registers 2-3
+        if buffer.get(1) == Some(&0x03) {
+            let mut frame = vec![0u8; 81];
+            frame[..3].copy_from_slice(&[0x01, 0x03, 76]);
+            frame[5..7].copy_from_slice(&180u16.to_be_bytes());
+            frame[7..11].copy_from_slice(&3.5f32.to_be_bytes());
+            self.pending.lock().extend_from_slice(&frame);
+        }
+        Ok(buffer.len())
+    }
+
+    pub fn flush(&self, _queue_type: Queue) -> rppal::uart::Result<()> {
+        self.pending.lock().clear();
+        Ok(())
+    }
+}
+
+/// An I2C bus with an AHT10 at 0x38, an INA219 at 0x40 and a BMP280 at 0x77 answering with
+/// fixed readings, other addresses don't acknowledge.
+#[derive(Debug)]
+pub struct I2c {
+    aht_calibrated: bool,
+    /// A read returns a measurement after a trigger command
+    aht_triggered: bool,
+    ina_registers: [u16; 6],
+    /// Register the next INA219 read returns
+    ina_pointer: usize,
+    bmp_pointer: u8,
+}
+
+impl I2c {
+    const AHT10: u16 = 0x38;
+    const INA219: u16 = 0x40;
+    const BMP280: u16 = 0x77;
+
+    /// 45 %RH and 21.5 °C
+    const AHT_HUMIDITY: u32 = 471_859;
+    const AHT_TEMPERATURE: u32 = 374_866;
+    /// Configuration register after a reset
+    const INA_CONFIGURATION: u16 = 0x399f;
+    /// 12 V on the bus and 10 mV across the shunt
+    const INA_BUS_MV: u16 = 12_000;
+    const INA_SHUNT_UV: u16 = 10_000;
+    /// Value of the BMP280 chip id register
+    pub const BMP_CHIP_ID: u8 = 0x58;
+
+    pub fn new() -> rppal::i2c::Result<Self> {
+        Self::with_bus(1)
+    }
+
+    pub fn with_bus(_bus: u8) -> rppal::i2c::Result<Self> {
+        let mut i2c = Self {
+            aht_calibrated: false,
+            aht_triggered: false,
+            ina_registers: [0; 6],
+            ina_pointer: 0,
+            bmp_pointer: 0,
+        };
+        i2c.reset_ina();
+        Ok(i2c)
+    }
+
+    fn nak() -> rppal::i2c::Error {
+        rppal::i2c::Error::Io(std::io::Error::from_raw_os_error(libc::EREMOTEIO))
+    }
+
+    fn reset_ina(&mut self) {
+        self.ina_registers = [
+            Self::INA_CONFIGURATION,
+            Self::INA_SHUNT_UV / 10,
+            // Conversion ready flag set
+            (Self::INA_BUS_MV / 4) << 3 | 0b10,
+            0,
+            0,
+            0,
+        ];
+    }
+
+    /// Current and power registers as the INA219 computes them from the calibration register
+    fn update_ina(&mut self) {
+        let shunt = u32::from(self.ina_registers[1]);
+        let calibration = u32::from(self.ina_registers[5]);
+        let current = shunt * calibration / 4096;
+        let bus = u32::from(self.ina_registers[2] >> 3);
+        #[allow(clippy::cast_possible_truncation)]
+        {
+            self.ina_registers[4] = current as u16;
+            self.ina_registers[3] = (current * bus / 5000) as u16;
+        }
+    }
+
+    fn write_bytes(&mut self, address: u16, bytes: &[u8]) -> Result<(), rppal::i2c::Error> {
+        match (address, bytes) {
+            (Self::AHT10, [0xe1, ..]) => self.aht_calibrated = true,
+            (Self::AHT10, [0xac, ..]) => self.aht_triggered = true,
+            (Self::AHT10, _) => {}
+            (Self::INA219, [register, rest @ ..]) if usize::from(*register) < 6 => {
+                self.ina_pointer = usize::from(*register);
+                if let [high, low] = rest {
+                    let value = u16::from_be_bytes([*high, *low]);
+                    match self.ina_pointer {
+                        0 if value & 0x8000 != 0 => self.reset_ina(),
+                        0 | 5 => self.ina_registers[self.ina_pointer] = value,
+                        _ => {}
+                    }
+                    self.update_ina();
+                }
+            }
+            (Self::BMP280, [register, ..]) => self.bmp_pointer = *register,
+            _ => return Err(Self::nak()),
+        }
+        Ok(())
+    }
+
+    fn read_bytes(&mut self, address: u16, buffer: &mut [u8]) -> Result<(), rppal::i2c::Error> {
+        match address {
+            Self::AHT10 => {
+                let (h, t) = (Self::AHT_HUMIDITY, Self::AHT_TEMPERATURE);
+                let mut status = if self.aht_calibrated { 0x08 } else { 0 };
+                if !std::mem::take(&mut self.aht_triggered) {
+                    // Busy
+                    status |= 0x80;
+                }
+                #[allow(clippy::cast_possible_truncation)]
+                let frame = [
+                    status,
+                    (h >> 12) as u8,
+                    (h >> 4) as u8,
+                    ((h & 0xf) << 4 | (t >> 16) & 0xf) as u8,
+                    (t >> 8) as u8,
+                    t as u8,
+                    0,
+                ];
+                for (byte, value) in buffer.iter_mut().zip(frame) {
+                    *byte = value;
+                }
+            }
+            Self::INA219 => {
+                let value = self.ina_registers[self.ina_pointer].to_be_bytes();
+                for (byte, value) in buffer.iter_mut().zip(value) {
+                    *byte = value;
+                }
+            }
+            Self::BMP280 => {
+                for byte in buffer.iter_mut() {
+                    *byte = if self.bmp_pointer == 0xd0 { Self::BMP_CHIP_ID } else { 0 };
+                    self.bmp_pointer = self.bmp_pointer.wrapping_add(1);
+                }
+            }
+            _ => return Err(Self::nak()),
+        }
+        Ok(())
+    }
+}
+
+impl embedded_hal_0::blocking::i2c::Write for I2c {
+    type Error = rppal::i2c::Error;
+
+    fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.write_bytes(u16::from(address), bytes)
+    }
+}
+
+impl embedded_hal_0::blocking::i2c::WriteRead for I2c {
+    type Error = rppal::i2c::Error;
+
+    fn write_read(
+        &mut self,
+        address: u8,
+        bytes: &[u8],
+        buffer: &mut [u8],
+    ) -> Result<(), Self::Error> {
+        self.write_bytes(u16::from(address), bytes)?;
+        self.read_bytes(u16::from(address), buffer)
+    }
+}
+
+impl ErrorType for I2c {
+    type Error = rppal::i2c::Error;
+}
+
+impl embedded_hal::i2c::I2c for I2c {
+    fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        for operation in operations {
+            match operation {
+                Operation::Write(bytes) => self.write_bytes(u16::from(address), bytes)?,
+                Operation::Read(buffer) => self.read_bytes(u16::from(address), buffer)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Stands in for the `bmp280` crate, which opens `/dev/i2c-1` itself. The sensor is detected
+/// on the fake bus by its chip id and reads a fixed 21.5 °C and 1010 hPa.
+pub mod bmp280 {
+    use embedded_hal::i2c::I2c as _;
+
+    #[derive(thiserror::Error, Debug)]
+    pub enum Error {
+        #[error("I2C error: {0}")]
+        I2c(#[from] rppal::i2c::Error),
+        #[error("Unexpected chip id {0:#04x}")]
+        ChipId(u8),
+    }
+
+    pub struct Bmp280Builder {
+        address: u8,
+    }
+
+    impl Bmp280Builder {
+        pub fn new() -> Self {
+            Self { address: 0x77 }
+        }
+
+        pub fn ground_pressure(self, _pa: f32) -> Self {
+            self
+        }
+
+        pub fn build(self) -> Result<Bmp280, Error> {
+            let mut i2c = super::I2c::new()?;
+            let mut id = [0];
+            i2c.write_read(self.address, &[0xd0], &mut id)?;
+            if id[0] != super::I2c::BMP_CHIP_ID {
+                return Err(Error::ChipId(id[0]));
+            }
+            Ok(Bmp280 { _i2c: i2c })
+        }
+    }
+
+    pub struct Bmp280 {
+        _i2c: super::I2c,
+    }
+
+    impl Bmp280 {
+        #[allow(clippy::unnecessary_wraps)]
+        pub fn temperature_celsius(&mut self) -> Result<f32, Error> {
+            Ok(21.5)
+        }
+
+        #[allow(clippy::unnecessary_wraps)]
+        pub fn pressure_kpa(&mut self) -> Result<f32, Error> {
+            Ok(101.0)
+        }
+    }
+}
+
+/// Delivers low level noise in real time
+pub struct Pcm {
+    channels: u32,
+    samplerate: u32,
+    /// Bytes per sample of the format
+    width: usize,
+    state: Cell<u32>,
+}
+
+impl Pcm {
+    pub fn new(channels: u32, samplerate: u32, format: Format) -> Self {
+        let width = match format {
+            Format::S16LE | Format::S16BE => 2,
+            Format::S243LE | Format::S243BE => 3,
+            _ => 4,
+        };
+        Self {
+            channels,
+            samplerate,
+            width,
+            state: Cell::new(1),
+        }
+    }
+
+    pub fn io_i32(&self) -> alsa::Result<IO<'_, i32>> {
+        Ok(IO(self, PhantomData))
+    }
+
+    pub fn io_i16(&self) -> alsa::Result<IO<'_, i16>> {
+        Ok(IO(self, PhantomData))
+    }
+
+    pub fn io_bytes(&self) -> IO<'_, u8> {
+        IO(self, PhantomData)
+    }
+}
+
+/// A sample type the fake PCM can fill buffers with
+pub trait Sample: Copy {
+    fn from_noise(noise: i32) -> Self;
+}
+
+impl Sample for i32 {
+    fn from_noise(noise: i32) -> Self {
+        noise
+    }
+}
+
+impl Sample for i16 {
+    #[allow(clippy::cast_possible_truncation)]
+    fn from_noise(noise: i32) -> Self {
+        (noise >> 16) as i16
+    }
+}
+
+impl Sample for u8 {
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    fn from_noise(noise: i32) -> Self {
+        noise as u8
+    }
+}
+
+pub struct IO<'a, S>(&'a Pcm, PhantomData<S>);
+
+impl<S: Sample> IO<'_, S> {
+    pub fn readi(&self, buf: &mut [S]) -> alsa::Result<usize> {
+        let pcm = self.0;
+        for sample in buf.iter_mut() {
+            let state = pcm.state.get().wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            pcm.state.set(state);
+            #[allow(clippy::cast_possible_wrap)]
+            let noise = (state as i32) >> 12;
+            *sample = S::from_noise(noise);
+        }
+        let frame_bytes = pcm.channels.max(1) as usize * pcm.width;
+        let frames = std::mem::size_of_val(buf) / frame_bytes;
+        #[allow(clippy::cast_precision_loss)]
+        let duration = frames as f64 / f64::from(pcm.samplerate.max(1));
+        thread::sleep(Duration::from_secs_f64(duration));
+        Ok(frames)
+    }
+}
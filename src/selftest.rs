@@ -0,0 +1,79 @@
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicBool, AtomicU8};
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+
+use crate::audio::CaptureDevice;
+use crate::data::aht::Aht;
+use crate::data::bmp::Bmp;
+use crate::data::device_manager::Settings;
+use crate::data::gps::Gps;
+use crate::data::imu::Imu;
+use crate::data::ina::Ina;
+use crate::data::wind::Wind;
+use crate::data::Device;
+use crate::{CaptureSettings, Config};
+
+fn check<T, E: Debug>(result: Result<T, E>) -> Result<(), String> {
+    result.map(|_| ()).map_err(|err| format!("{err:?}"))
+}
+
+fn probe_capture_device(settings: &CaptureSettings) -> Result<(), String> {
+    let running = AtomicBool::new(true);
+    let status = AtomicU8::new(0);
+    let device = CaptureDevice::new(
+        &settings.devices,
+        settings.channels,
+        settings.samplerate,
+        settings.format,
+        ".",
+        &running,
+        &status,
+        Arc::new(Mutex::new((false, 0i64))),
+    );
+    check(device.probe())
+}
+
+/// Probes every sensor and capture device once and prints a pass/fail table.
+///
+/// Returns `true` if every device passed.
+pub fn run(config: &Config) -> bool {
+    let mut settings = Settings::default();
+    config.sensors.apply(&mut settings);
+    let calib_path = &config.andros_dir;
+
+    let results = [
+        (
+            "gps",
+            check(
+                Gps::init(&settings.gps).and_then(|mut gps| gps.get_data()),
+            ),
+        ),
+        ("aht", check(Aht::init(&settings.aht_bus).and_then(|mut aht| aht.get_data()))),
+        ("bmp", check(Bmp::init(&settings.bmp).and_then(|mut bmp| bmp.get_data()))),
+        ("ina", check(Ina::init(&settings.ina_calibration).and_then(|mut ina| ina.get_data()))),
+        (
+            "wind",
+            check(
+                Wind::init(&settings.wind).and_then(|mut wind| wind.get_data()),
+            ),
+        ),
+        (
+            "imu",
+            check(Imu::new(settings.imu_bus, 1, calib_path).and_then(|mut imu| imu.get_data())),
+        ),
+        ("i2s", probe_capture_device(&config.i2s)),
+        ("umc", probe_capture_device(&config.umc)),
+    ];
+
+    println!("{:<8}{:<8}ERROR", "DEVICE", "RESULT");
+    for (device, result) in &results {
+        match result {
+            Ok(()) => println!("{device:<8}PASS"),
+            Err(err) => println!("{device:<8}FAIL    {err}"),
+        }
+    }
+
+    results.iter().all(|(_, result)| result.is_ok())
+}
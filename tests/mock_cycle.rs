@@ -0,0 +1,68 @@
+//! Runs the whole pipeline against the fake hardware until a cycle reports every faked sensor.
+#![cfg(feature = "mock-hardware")]
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use mics::{run, Config};
+use serde_json::Value;
+
+/// Two read cycles, the first one only initializes the I2C sensors
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The latest cycle once it has data from all faked sensors
+fn complete_cycle(link: &std::path::Path) -> Option<Value> {
+    let value: Value = serde_json::from_str(&std::fs::read_to_string(link).ok()?).ok()?;
+    let data = &value["data"];
+    ["gps", "aht", "wind", "bmp", "ina"]
+        .iter()
+        .all(|sensor| !data[sensor].is_null())
+        .then_some(value)
+}
+
+#[test]
+fn reader_cycle() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = "[sensors]\nina_shunt_uohm = 100000\n\n[health]\nmin_free_gb = 0.0\n";
+    std::fs::write(dir.path().join("config.toml"), config).unwrap();
+    let config = Config::load(dir.path()).unwrap();
+    let running = AtomicBool::new(true);
+
+    let value = thread::scope(|s| {
+        let pipeline = s.spawn(|| run(config, &running));
+        let link = dir.path().join("data/data/data.json");
+        let start = Instant::now();
+        let value = loop {
+            if let Some(value) = complete_cycle(&link) {
+                break Some(value);
+            }
+            if start.elapsed() > TIMEOUT || pipeline.is_finished() {
+                break None;
+            }
+            thread::sleep(Duration::from_millis(200));
+        };
+        running.store(false, Ordering::Relaxed);
+        pipeline.join().unwrap().unwrap();
+        value
+    });
+    let value = value.expect("no cycle with data from every faked sensor");
+
+    let (data, statuses) = (&value["data"], &value["statuses"]);
+    for sensor in ["gps", "aht", "wind", "bmp", "ina"] {
+        assert_eq!(statuses[sensor], "Ok", "{sensor}");
+    }
+    // No IMU is faked
+    assert_eq!(statuses["imu"], "Disconnected");
+
+    let close = |value: &Value, expected: f64| (value.as_f64().unwrap() - expected).abs() < 0.1;
+    assert!(close(&data["gps"]["latitude"], 48.1173));
+    assert!(close(&data["aht"]["humidity"], 45.0));
+    assert!(close(&data["aht"]["temperature"], 21.5));
+    assert!(close(&data["wind"]["speed"], 3.5));
+    assert!(close(&data["bmp"]["pressure"], 1010.0));
+    assert_eq!(data["ina"]["bus_voltage"], 12_000);
+    assert_eq!(data["ina"]["calibrated"], true);
+    // 10 mV across 0.1 ohm
+    assert!(close(&data["ina"]["current"], 100.0));
+}